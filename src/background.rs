@@ -0,0 +1,361 @@
+//! Exporting documents from a dedicated worker thread
+
+use crate::{exporter::Exporter, types::types::Document};
+use std::{
+    collections::VecDeque,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// What to do with a document when the export queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DropPolicy {
+    /// Discard the document being exported
+    #[default]
+    DropNewest,
+    /// Discard the document which has waited longest, making room for the
+    /// document being exported
+    DropOldest,
+    /// Block the thread closing the span until there is room in the queue
+    Block,
+}
+
+/// Documents waiting to be exported, shared between the layer and the worker
+struct Queue {
+    state: Mutex<State>,
+    /// Signalled when a document is queued or the worker should shut down
+    queued: Condvar,
+    /// Signalled whenever the worker finishes exporting a document
+    exported: Condvar,
+    capacity: usize,
+    policy: DropPolicy,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    documents: VecDeque<Document>,
+    in_flight: bool,
+    shutdown: bool,
+}
+
+impl Queue {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("export queue lock poisoned")
+    }
+
+    /// Blocks until the worker has exported every queued document, returning
+    /// at once if the worker has been shut down
+    fn flush(&self) {
+        let mut state = self.state();
+        while !state.shutdown && (!state.documents.is_empty() || state.in_flight) {
+            state = self
+                .exported
+                .wait(state)
+                .expect("export queue lock poisoned");
+        }
+    }
+}
+
+/// Hands documents to the worker thread rather than exporting them in place
+pub(crate) struct BackgroundExporter {
+    queue: Arc<Queue>,
+    /// Whether a worker is exporting queued documents
+    draining: bool,
+}
+
+impl Exporter for BackgroundExporter {
+    fn export(&self, document: Document) -> io::Result<()> {
+        let queue = &self.queue;
+        let mut state = queue.state();
+        // once the guard is dropped, nothing drains the queue
+        if state.shutdown {
+            queue.dropped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        if state.documents.len() >= queue.capacity {
+            match queue.policy {
+                DropPolicy::DropNewest => {
+                    queue.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                DropPolicy::DropOldest => {
+                    state.documents.pop_front();
+                    queue.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                DropPolicy::Block => {
+                    while state.documents.len() >= queue.capacity {
+                        if state.shutdown {
+                            queue.dropped.fetch_add(1, Ordering::Relaxed);
+                            return Ok(());
+                        }
+                        state = queue
+                            .exported
+                            .wait(state)
+                            .expect("export queue lock poisoned");
+                    }
+                }
+            }
+        }
+        state.documents.push_back(document);
+        queue.queued.notify_one();
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.draining {
+            self.queue.flush();
+        }
+        Ok(())
+    }
+}
+
+/// Flushes documents waiting to be exported when dropped
+///
+/// Returned by [`Builder::build_non_blocking`] alongside the layer, the guard
+/// owns the worker thread exporting documents. It should be held until the
+/// program exits, so that short-lived processes do not lose traces:
+///
+/// ```
+/// use tracing_xray::XRay;
+/// use tracing_subscriber::{layer::SubscriberExt, Registry};
+///
+/// let (layer, _guard) = XRay::builder().build_non_blocking();
+/// tracing::subscriber::set_global_default(Registry::default().with(layer))
+///     .expect("failed to install subscriber");
+/// ```
+///
+/// [`Builder::build_non_blocking`]: crate::Builder::build_non_blocking
+#[must_use = "documents are only flushed when the guard is dropped"]
+pub struct XRayGuard {
+    queue: Arc<Queue>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl XRayGuard {
+    /// Blocks until every document queued so far has been exported
+    pub fn flush(&self) {
+        if self.worker.is_some() {
+            self.queue.flush();
+        }
+    }
+
+    /// The number of documents exported successfully
+    pub fn sent(&self) -> u64 {
+        self.queue.sent.load(Ordering::Relaxed)
+    }
+
+    /// The number of documents discarded because the queue was full, or
+    /// because they were exported after the guard was dropped
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The number of documents the exporter failed to export
+    pub fn failed(&self) -> u64 {
+        self.queue.failed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for XRayGuard {
+    fn drop(&mut self) {
+        self.flush();
+        self.queue.state().shutdown = true;
+        self.queue.queued.notify_all();
+        self.queue.exported.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Spawns a worker exporting documents to `exporter`, if there is one
+///
+/// Without an exporter, documents are queued until they are dropped according
+/// to `policy`, which is never [`DropPolicy::Block`].
+pub(crate) fn spawn(
+    exporter: Option<Box<dyn Exporter>>,
+    capacity: usize,
+    policy: DropPolicy,
+) -> (BackgroundExporter, XRayGuard) {
+    let queue = Arc::new(Queue {
+        state: Mutex::default(),
+        queued: Condvar::new(),
+        exported: Condvar::new(),
+        capacity,
+        policy: match (&exporter, policy) {
+            (None, DropPolicy::Block) => DropPolicy::DropNewest,
+            (_, policy) => policy,
+        },
+        sent: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+        failed: AtomicU64::new(0),
+    });
+    let worker = exporter.map(|exporter| {
+        let queue = queue.clone();
+        thread::Builder::new()
+            .name("xray-export".into())
+            .spawn(move || work(&queue, exporter))
+            .expect("failed to spawn export worker")
+    });
+    (
+        BackgroundExporter {
+            queue: queue.clone(),
+            draining: worker.is_some(),
+        },
+        XRayGuard { queue, worker },
+    )
+}
+
+fn work(queue: &Queue, exporter: Box<dyn Exporter>) {
+    loop {
+        let document = {
+            let mut state = queue.state();
+            loop {
+                if let Some(document) = state.documents.pop_front() {
+                    state.in_flight = true;
+                    break document;
+                }
+                if state.shutdown {
+                    return;
+                }
+                state = queue
+                    .queued
+                    .wait(state)
+                    .expect("export queue lock poisoned");
+            }
+        };
+        // a panicking exporter counts as a failure rather than leaving the
+        // document in flight forever
+        let result = panic::catch_unwind(AssertUnwindSafe(|| exporter.export(document)));
+        let counter = match result {
+            Ok(Ok(())) => &queue.sent,
+            Ok(Err(_)) | Err(_) => &queue.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        queue.state().in_flight = false;
+        queue.exported.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::types::Segment;
+    use std::{
+        sync::mpsc::{channel, Sender},
+        time::Duration,
+    };
+
+    type Names = Arc<Mutex<Vec<String>>>;
+
+    /// Exports the names of documents once `release` receives a message
+    fn gated() -> (Box<dyn Exporter>, Sender<()>, Names) {
+        let (release, gate) = channel::<()>();
+        let gate = Mutex::new(gate);
+        let exported = Arc::new(Mutex::new(Vec::new()));
+        let names = exported.clone();
+        let exporter = move |document: Document| {
+            let _ = gate.lock().unwrap().recv_timeout(Duration::from_secs(5));
+            if let Document::Segment(segment) = document {
+                names.lock().unwrap().push(segment.name);
+            }
+            Ok(())
+        };
+        (Box::new(exporter), release, exported)
+    }
+
+    #[test]
+    fn flushes_on_request() {
+        let (exporter, guard) = spawn(Some(Box::new(|_| Ok(()))), 16, DropPolicy::Block);
+        for _ in 0..10 {
+            exporter.export(Segment::begin("test").into()).unwrap();
+        }
+        guard.flush();
+        assert_eq!(guard.sent(), 10);
+        assert_eq!(guard.dropped(), 0);
+    }
+
+    #[test]
+    fn flushes_on_drop() {
+        let (inner, release, exported) = gated();
+        let (exporter, guard) = spawn(Some(inner), 16, DropPolicy::Block);
+        for name in &["first", "second", "third"] {
+            exporter.export(Segment::begin(*name).into()).unwrap();
+        }
+        drop(release);
+        drop(guard);
+        assert_eq!(*exported.lock().unwrap(), ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn drops_newest_when_full() {
+        let (inner, release, exported) = gated();
+        let (exporter, guard) = spawn(Some(inner), 1, DropPolicy::DropNewest);
+        exporter.export(Segment::begin("first").into()).unwrap();
+        // wait for the worker to take the first document, leaving the queue empty
+        while !guard.queue.state().in_flight {
+            thread::yield_now();
+        }
+        for name in &["second", "third"] {
+            exporter.export(Segment::begin(*name).into()).unwrap();
+        }
+        drop(release);
+        drop(guard);
+        assert_eq!(*exported.lock().unwrap(), ["first", "second"]);
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let (inner, release, exported) = gated();
+        let (exporter, guard) = spawn(Some(inner), 1, DropPolicy::DropOldest);
+        exporter.export(Segment::begin("first").into()).unwrap();
+        while !guard.queue.state().in_flight {
+            thread::yield_now();
+        }
+        for name in &["second", "third"] {
+            exporter.export(Segment::begin(*name).into()).unwrap();
+        }
+        assert_eq!(guard.dropped(), 1);
+        drop(release);
+        drop(guard);
+        assert_eq!(*exported.lock().unwrap(), ["first", "third"]);
+    }
+
+    #[test]
+    fn counts_failures() {
+        let failing = |_: Document| Err(io::Error::other("daemon unavailable"));
+        let (exporter, guard) = spawn(Some(Box::new(failing)), 16, DropPolicy::Block);
+        exporter.export(Segment::begin("test").into()).unwrap();
+        guard.flush();
+        assert_eq!(guard.failed(), 1);
+        assert_eq!(guard.sent(), 0);
+    }
+
+    #[test]
+    fn counts_panics_as_failures() {
+        let panicking = |_: Document| -> io::Result<()> { panic!("exporter bug") };
+        let (exporter, guard) = spawn(Some(Box::new(panicking)), 16, DropPolicy::Block);
+        exporter.export(Segment::begin("test").into()).unwrap();
+        guard.flush();
+        assert_eq!(guard.failed(), 1);
+    }
+
+    #[test]
+    fn drops_documents_after_shutdown() {
+        let (exporter, guard) = spawn(Some(Box::new(|_| Ok(()))), 1, DropPolicy::Block);
+        drop(guard);
+        for _ in 0..2 {
+            exporter.export(Segment::begin("test").into()).unwrap();
+        }
+        exporter.flush().unwrap();
+        assert_eq!(exporter.queue.dropped.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,396 @@
+//! Configuration of the [`XRay`] layer
+
+#[cfg(feature = "centralized-sampling")]
+use crate::sampling::CentralizedSampler;
+use crate::{
+    background::{self, DropPolicy, XRayGuard},
+    config::{self, ContextMissing, DaemonAddress},
+    exporter::{Exporter, UdpExporter},
+    in_progress::InProgress,
+    plugins::Plugin,
+    sampling::{LocalSampler, Sampler},
+    XRay,
+};
+use std::{env, io, sync::Arc, time::Duration};
+use tracing::Level;
+
+/// Configures and builds an [`XRay`] layer
+///
+/// ```
+/// use tracing_xray::{UdpExporter, XRay};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let layer = XRay::builder()
+///     .exporter(UdpExporter::new()?)
+///     .annotation_prefix("xray.annotation.")
+///     .build();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Builder {
+    exporter: Option<Box<dyn Exporter>>,
+    annotation_prefix: Option<String>,
+    log_events: Option<Level>,
+    sampler: Option<Box<dyn Sampler>>,
+    service_name: Option<String>,
+    context_missing: ContextMissing,
+    disabled: bool,
+    trace_header_from_env: bool,
+    queue_capacity: Option<usize>,
+    drop_policy: DropPolicy,
+    in_progress_after: Option<Duration>,
+    lambda: bool,
+    plugins: Vec<Box<dyn Plugin>>,
+}
+
+impl Builder {
+    /// Configures a layer the way the X-Ray SDKs for other languages configure
+    /// themselves from the environment
+    ///
+    /// - `AWS_XRAY_DAEMON_ADDRESS` sets the daemon segments are sent to and
+    ///   sampling rules are fetched from, defaulting to `127.0.0.1:2000`
+    /// - `AWS_XRAY_TRACING_NAME` sets the [service name]
+    /// - `AWS_XRAY_CONTEXT_MISSING` sets the [context missing] strategy
+    /// - `AWS_XRAY_SDK_ENABLED` disables the layer when `false`
+    /// - `_X_AMZN_TRACE_ID` is [read for each root span] which does not
+    ///   record its own tracing header
+    /// - `LAMBDA_TASK_ROOT`, set by AWS Lambda, enables [Lambda mode]
+    ///
+    /// Segments are sent over UDP, and outside of Lambda are sampled by a
+    /// [`CentralizedSampler`] when the `centralized-sampling` feature is
+    /// enabled, as it is by default.
+    ///
+    /// [service name]: Builder::service_name
+    /// [Lambda mode]: Builder::lambda
+    /// [context missing]: Builder::context_missing
+    /// [read for each root span]: Builder::trace_header_from_env
+    pub fn from_env() -> io::Result<Self> {
+        Builder::from_lookup(|key| env::var(key).ok())
+    }
+
+    fn from_lookup<F>(lookup: F) -> io::Result<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let invalid = |error| io::Error::new(io::ErrorKind::InvalidInput, error);
+        let mut builder = Builder::default();
+        if let Some(enabled) = lookup(config::SDK_ENABLED) {
+            if enabled.eq_ignore_ascii_case("false") {
+                return Ok(builder.enabled(false));
+            }
+        }
+        let daemon = match lookup(config::DAEMON_ADDRESS) {
+            Some(address) => address.parse::<DaemonAddress>().map_err(invalid)?,
+            None => DaemonAddress::default(),
+        };
+        builder = builder
+            .exporter(UdpExporter::with_address(daemon.udp)?)
+            .trace_header_from_env(true);
+        // Lambda makes the sampling decision for each invocation itself
+        if lookup(config::LAMBDA_TASK_ROOT).is_some() {
+            builder = builder.lambda(true);
+        } else {
+            #[cfg(feature = "centralized-sampling")]
+            {
+                let endpoint = format!("http://{}", daemon.tcp);
+                builder = builder.sampler(CentralizedSampler::new(endpoint).start());
+            }
+        }
+        if let Some(name) = lookup(config::TRACING_NAME) {
+            builder = builder.service_name(name);
+        }
+        if let Some(strategy) = lookup(config::CONTEXT_MISSING) {
+            builder = builder.context_missing(strategy.parse().map_err(invalid)?);
+        }
+        Ok(builder)
+    }
+
+    /// Sends completed segments to `exporter`
+    pub fn exporter<E>(mut self, exporter: E) -> Self
+    where
+        E: Exporter,
+    {
+        self.exporter = Some(Box::new(exporter));
+        self
+    }
+
+    /// Only records span fields named with `prefix` as annotations
+    ///
+    /// The prefix is removed from the annotation's key, and every other field
+    /// is recorded as metadata. Without a prefix, boolean, numeric and string
+    /// fields become annotations and any other value becomes metadata.
+    pub fn annotation_prefix<P>(mut self, prefix: P) -> Self
+    where
+        P: Into<String>,
+    {
+        self.annotation_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Attaches events at `level` or above to the segment of the span they
+    /// occur in, as a timestamped log array
+    ///
+    /// The log is recorded in the segment's metadata as `tracing.events`.
+    /// Regardless of this setting, `ERROR` events are recorded as exceptions.
+    pub fn log_events(mut self, level: Level) -> Self {
+        self.log_events = Some(level);
+        self
+    }
+
+    /// Decides which new traces are recorded using `sampler`
+    ///
+    /// Defaults to a [`LocalSampler`] recording one request each second and 5%
+    /// of any additional requests.
+    pub fn sampler<S>(mut self, sampler: S) -> Self
+    where
+        S: Sampler,
+    {
+        self.sampler = Some(Box::new(sampler));
+        self
+    }
+
+    /// Names every root segment `name`, rather than after its span
+    ///
+    /// A segment's name should match the domain name or logical name of the
+    /// service that generates it.
+    pub fn service_name<N>(mut self, name: N) -> Self
+    where
+        N: Into<String>,
+    {
+        self.service_name = Some(name.into());
+        self
+    }
+
+    /// Sets what happens when there is no segment to record work in, such as
+    /// a Lambda invocation without a trace header, or a request sent by the
+    /// `tower` or `reqwest` client instrumentation outside of a traced span
+    ///
+    /// Defaults to [`ContextMissing::IgnoreError`].
+    pub fn context_missing(mut self, strategy: ContextMissing) -> Self {
+        self.context_missing = strategy;
+        self
+    }
+
+    /// Records and exports nothing when `enabled` is false
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.disabled = !enabled;
+        self
+    }
+
+    /// Continues the trace in the `_X_AMZN_TRACE_ID` environment variable
+    /// for root spans which do not record their own tracing header
+    ///
+    /// The variable is read as each root span is created, as runtimes such as
+    /// AWS Lambda set it for each invocation.
+    pub fn trace_header_from_env(mut self, enabled: bool) -> Self {
+        self.trace_header_from_env = enabled;
+        self
+    }
+
+    /// Exports an in-progress segment for root spans open longer than
+    /// `threshold`, ahead of the complete segment when they close
+    ///
+    /// A timer thread exports the segment once the threshold passes, whether
+    /// or not there is any activity within the span, so that long-polling and
+    /// batch requests appear in the console while they are still running. The
+    /// in-progress segment carries the fields recorded on the span so far, and
+    /// the complete segment replaces it.
+    pub fn stream_in_progress(mut self, threshold: Duration) -> Self {
+        self.in_progress_after = Some(threshold);
+        self
+    }
+
+    /// Describes the environment the application runs in on every segment
+    ///
+    /// Plugins are applied in the order they are added, and the first to set
+    /// a segment's origin names it, so more specific environments should be
+    /// added first.
+    pub fn plugin<P>(mut self, plugin: P) -> Self
+    where
+        P: Plugin,
+    {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    /// Records spans as AWS Lambda expects when `lambda` is true
+    ///
+    /// Lambda records a segment for each invocation itself, so spans without
+    /// a traced parent become independent subsegments of it rather than
+    /// segments. The invocation's trace is read from the span's
+    /// `X-Amzn-Trace-Id` field, which may hold the `Lambda-Runtime-Trace-Id`
+    /// header of the invocation, or else the `_X_AMZN_TRACE_ID` environment
+    /// variable; spans without either are handled according to
+    /// [`context_missing`](Builder::context_missing). Lambda's sampling
+    /// decision is always followed, and the exporter is flushed as each
+    /// subsegment is exported, so that nothing is lost when the invocation
+    /// ends and the function is frozen.
+    pub fn lambda(mut self, lambda: bool) -> Self {
+        self.lambda = lambda;
+        self
+    }
+
+    /// Sets how many documents may wait to be exported by
+    /// [`build_non_blocking`] layers, defaulting to 1024
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero, as no document could ever be queued.
+    ///
+    /// [`build_non_blocking`]: Builder::build_non_blocking
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "export queue capacity must be at least 1");
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Sets what [`build_non_blocking`] layers do with documents when the
+    /// export queue is full, defaulting to [`DropPolicy::DropNewest`]
+    ///
+    /// [`build_non_blocking`]: Builder::build_non_blocking
+    pub fn drop_policy(mut self, policy: DropPolicy) -> Self {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Builds the configured layer, exporting documents from a dedicated
+    /// worker thread
+    ///
+    /// Documents are queued as spans close, keeping the exporter's I/O off
+    /// the application's threads. Pending documents are flushed when the
+    /// returned guard is dropped.
+    pub fn build_non_blocking(mut self) -> (XRay, XRayGuard) {
+        let (exporter, guard) = background::spawn(
+            self.exporter.take(),
+            self.queue_capacity.unwrap_or(1024),
+            self.drop_policy,
+        );
+        (self.exporter(exporter).build(), guard)
+    }
+
+    /// Builds the configured layer, exporting documents on the thread which
+    /// closes their span
+    pub fn build(self) -> XRay {
+        let exporter = self.exporter.map(Arc::<dyn Exporter>::from);
+        let in_progress = self
+            .in_progress_after
+            .zip(exporter.clone())
+            .map(|(threshold, exporter)| InProgress::spawn(threshold, exporter));
+        XRay {
+            exporter,
+            annotation_prefix: self.annotation_prefix,
+            log_events: self.log_events,
+            sampler: self
+                .sampler
+                .unwrap_or_else(|| Box::new(LocalSampler::default())),
+            service_name: self.service_name,
+            context_missing: self.context_missing,
+            enabled: !self.disabled,
+            trace_header_from_env: self.trace_header_from_env,
+            in_progress,
+            lambda: self.lambda,
+            plugins: self.plugins,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, net::UdpSocket};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn configures_from_environment() -> io::Result<()> {
+        let daemon = UdpSocket::bind("127.0.0.1:0")?;
+        let address = daemon.local_addr()?.to_string();
+        let layer = Builder::from_lookup(lookup(&[
+            (config::DAEMON_ADDRESS, &address),
+            (config::TRACING_NAME, "checkout"),
+            (config::CONTEXT_MISSING, "IGNORE_ERROR"),
+        ]))?
+        .build();
+        assert_eq!(layer.context_missing, ContextMissing::IgnoreError);
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info_span!("handler").in_scope(|| {});
+        });
+        let mut buf = [0; 65_536];
+        let len = daemon.recv(&mut buf)?;
+        let message = String::from_utf8_lossy(&buf[..len]);
+        assert!(message.contains(r#""name":"checkout""#), "{}", message);
+        Ok(())
+    }
+
+    #[test]
+    fn disabled_from_environment() -> io::Result<()> {
+        let layer = Builder::from_lookup(lookup(&[(config::SDK_ENABLED, "FALSE")]))?
+            .exporter(|_| panic!("disabled layer exported a segment"))
+            .build();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info_span!("handler").in_scope(|| tracing::error!("failed"));
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn flushes_invocations_in_lambda() -> io::Result<()> {
+        let exported = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let invocations = exported.clone();
+        let (layer, guard) =
+            Builder::from_lookup(lookup(&[(config::LAMBDA_TASK_ROOT, "/var/task")]))?
+                .exporter(move |document| {
+                    invocations.lock().unwrap().push(document);
+                    Ok(())
+                })
+                .build_non_blocking();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let header =
+                "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+            tracing::info_span!("invocation", "X-Amzn-Trace-Id" = header).in_scope(|| {});
+            // exported before the guard is flushed or dropped
+            assert_eq!(exported.lock().unwrap().len(), 1);
+        });
+        drop(guard);
+        Ok(())
+    }
+
+    #[test]
+    fn exports_from_worker_thread() {
+        let exported = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let threads = exported.clone();
+        let (layer, guard) = XRay::builder()
+            .exporter(move |_| {
+                threads.lock().unwrap().push(std::thread::current().id());
+                Ok(())
+            })
+            .build_non_blocking();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info_span!("handler").in_scope(|| {});
+        });
+        drop(guard);
+        let exported = exported.lock().unwrap();
+        assert_eq!(exported.len(), 1);
+        assert_ne!(exported[0], std::thread::current().id());
+    }
+
+    #[test]
+    fn rejects_invalid_environment() {
+        assert!(Builder::from_lookup(lookup(&[(config::DAEMON_ADDRESS, "tcp:nowhere")])).is_err());
+        assert!(Builder::from_lookup(lookup(&[(config::CONTEXT_MISSING, "PANIC")])).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity must be at least 1")]
+    fn rejects_empty_queue() {
+        let _ = Builder::default().queue_capacity(0);
+    }
+}
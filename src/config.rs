@@ -0,0 +1,150 @@
+//! Settings shared with the X-Ray SDKs for other languages
+
+use std::{
+    fmt,
+    net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+};
+
+/// Environment variable naming the X-Ray daemon's address
+pub const DAEMON_ADDRESS: &str = "AWS_XRAY_DAEMON_ADDRESS";
+/// Environment variable naming the service in root segments
+pub const TRACING_NAME: &str = "AWS_XRAY_TRACING_NAME";
+/// Environment variable selecting the [`ContextMissing`] strategy
+pub const CONTEXT_MISSING: &str = "AWS_XRAY_CONTEXT_MISSING";
+/// Environment variable which disables the layer when `false`
+pub const SDK_ENABLED: &str = "AWS_XRAY_SDK_ENABLED";
+/// Environment variable holding the tracing header of the current invocation,
+/// set by runtimes such as AWS Lambda
+pub const TRACE_ID: &str = "_X_AMZN_TRACE_ID";
+/// Environment variable set by AWS Lambda to the path of the function's code,
+/// which enables [`Builder::lambda`](crate::Builder::lambda) mode
+pub const LAMBDA_TASK_ROOT: &str = "LAMBDA_TASK_ROOT";
+/// Environment variable set by Amazon ECS to the container metadata endpoint,
+/// which [`EcsPlugin`](crate::plugins::EcsPlugin) reads
+pub const ECS_CONTAINER_METADATA_URI: &str = "ECS_CONTAINER_METADATA_URI_V4";
+
+/// Addresses of the X-Ray daemon
+///
+/// Parsed from either a single `host:port` used for both protocols, or
+/// separate `tcp:host:port udp:host:port` addresses in either order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DaemonAddress {
+    /// Address segments are sent to over UDP
+    pub udp: SocketAddr,
+    /// Address of the daemon's proxy for sampling rules over TCP
+    pub tcp: SocketAddr,
+}
+
+impl Default for DaemonAddress {
+    fn default() -> Self {
+        let address = ([127, 0, 0, 1], 2000).into();
+        DaemonAddress {
+            udp: address,
+            tcp: address,
+        }
+    }
+}
+
+impl FromStr for DaemonAddress {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let resolve = |address: &str| {
+            address
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addresses| addresses.next())
+                .ok_or_else(|| format!("invalid daemon address `{}`", address))
+        };
+        let parts = s.split_whitespace().collect::<Vec<_>>();
+        match parts.as_slice() {
+            [address] => {
+                let address = resolve(address)?;
+                Ok(DaemonAddress {
+                    udp: address,
+                    tcp: address,
+                })
+            }
+            [first, second] => {
+                let (mut udp, mut tcp) = (None, None);
+                for part in &[first, second] {
+                    if let Some(address) = part.strip_prefix("udp:") {
+                        udp = Some(resolve(address)?);
+                    } else if let Some(address) = part.strip_prefix("tcp:") {
+                        tcp = Some(resolve(address)?);
+                    }
+                }
+                match (udp, tcp) {
+                    (Some(udp), Some(tcp)) => Ok(DaemonAddress { udp, tcp }),
+                    _ => Err(format!(
+                        "invalid daemon address `{}`: expected `tcp:host:port udp:host:port`",
+                        s
+                    )),
+                }
+            }
+            _ => Err(format!("invalid daemon address `{}`", s)),
+        }
+    }
+}
+
+/// What to do when instrumentation needs a segment which does not exist
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ContextMissing {
+    /// Panic
+    RuntimeError,
+    /// Print the error to standard error
+    LogError,
+    /// Do nothing
+    #[default]
+    IgnoreError,
+}
+
+impl ContextMissing {
+    /// Reports that `what` could not be recorded
+    pub(crate) fn handle(self, what: fmt::Arguments) {
+        match self {
+            ContextMissing::RuntimeError => panic!("X-Ray context missing: {}", what),
+            ContextMissing::LogError => eprintln!("X-Ray context missing: {}", what),
+            ContextMissing::IgnoreError => (),
+        }
+    }
+}
+
+impl FromStr for ContextMissing {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "RUNTIME_ERROR" => Ok(ContextMissing::RuntimeError),
+            "LOG_ERROR" => Ok(ContextMissing::LogError),
+            "IGNORE_ERROR" => Ok(ContextMissing::IgnoreError),
+            _ => Err(format!("invalid context missing strategy `{}`", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_daemon_address() {
+        assert_eq!(
+            "127.0.0.1:3000".parse::<DaemonAddress>(),
+            Ok(DaemonAddress {
+                udp: ([127, 0, 0, 1], 3000).into(),
+                tcp: ([127, 0, 0, 1], 3000).into(),
+            })
+        );
+        assert_eq!(
+            "udp:127.0.0.1:3000 tcp:127.0.0.2:2000".parse::<DaemonAddress>(),
+            Ok(DaemonAddress {
+                udp: ([127, 0, 0, 1], 3000).into(),
+                tcp: ([127, 0, 0, 2], 2000).into(),
+            })
+        );
+        assert!("tcp:127.0.0.1:3000".parse::<DaemonAddress>().is_err());
+        assert!("udp:127.0.0.1:3000 tcp:nonsense"
+            .parse::<DaemonAddress>()
+            .is_err());
+    }
+}
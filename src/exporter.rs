@@ -0,0 +1,203 @@
+//! Destinations for completed segments
+
+use crate::types::types::{Document, Subsegment};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+};
+
+/// Receives each segment document once the span it describes has closed
+///
+/// Documents are usually complete segments with their subsegments embedded,
+/// but may also be independent subsegments. The layer itself is agnostic about
+/// where documents go: an exporter may forward them to the X-Ray daemon, buffer
+/// them for a batch upload or simply collect them in memory for tests. Errors
+/// are reported back to the layer, which has nowhere to surface them and so
+/// discards them.
+pub trait Exporter: Send + Sync + 'static {
+    /// Export a single completed document
+    fn export(&self, document: Document) -> io::Result<()>;
+
+    /// Blocks until every document exported so far has been sent
+    ///
+    /// Exporters which send documents in place have nothing to flush.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F> Exporter for F
+where
+    F: Fn(Document) -> io::Result<()> + Send + Sync + 'static,
+{
+    fn export(&self, document: Document) -> io::Result<()> {
+        self(document)
+    }
+}
+
+/// Sends documents to the X-Ray daemon over UDP
+///
+/// Each document is serialized as JSON and sent as a single datagram, prefixed
+/// with the daemon's protocol header line. Documents too large for a single
+/// datagram have their embedded subsegments sent as independent documents
+/// instead, recursively, so that large traces are split rather than lost.
+#[derive(Debug)]
+pub struct UdpExporter {
+    socket: UdpSocket,
+    daemon: SocketAddr,
+}
+
+impl UdpExporter {
+    /// Address the X-Ray daemon listens on unless configured otherwise
+    pub const DEFAULT_ADDRESS: SocketAddr =
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2000));
+
+    /// Line preceding every segment document sent to the daemon
+    const HEADER: &'static [u8] = b"{\"format\": \"json\", \"version\": 1}\n";
+
+    /// The largest datagram the daemon accepts, which is the largest UDP
+    /// payload over IPv4
+    const MAX_DATAGRAM: usize = 65_507;
+
+    /// Creates an exporter which sends to a daemon at the default address
+    pub fn new() -> io::Result<Self> {
+        UdpExporter::with_address(UdpExporter::DEFAULT_ADDRESS)
+    }
+
+    /// Creates an exporter which sends to a daemon at `address`
+    pub fn with_address<A>(address: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let daemon = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no daemon address resolved")
+        })?;
+        let local: SocketAddr = if daemon.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            "[::]:0".parse().expect("valid ipv6 address")
+        };
+        Ok(UdpExporter {
+            socket: UdpSocket::bind(local)?,
+            daemon,
+        })
+    }
+
+    /// The address segments are sent to
+    pub fn daemon_address(&self) -> SocketAddr {
+        self.daemon
+    }
+}
+
+impl Exporter for UdpExporter {
+    fn export(&self, mut document: Document) -> io::Result<()> {
+        let mut message = UdpExporter::HEADER.to_vec();
+        serde_json::to_writer(&mut message, &document)?;
+        if message.len() <= UdpExporter::MAX_DATAGRAM {
+            self.socket.send_to(&message, self.daemon)?;
+            return Ok(());
+        }
+        let parent = match &mut document {
+            Document::Segment(segment) => {
+                Some((segment.trace_id, segment.id, &mut segment.subsegments))
+            }
+            Document::Subsegment(Subsegment {
+                independent: Some(independent),
+                id,
+                subsegments,
+                ..
+            }) => Some((independent.trace_id, *id, subsegments)),
+            Document::Subsegment(_) => None,
+        };
+        let (trace_id, parent_id, subsegments) = match parent {
+            Some((_, _, subsegments)) if subsegments.is_empty() => None,
+            parent => parent,
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} byte document exceeds the daemon's {} byte limit",
+                    message.len(),
+                    UdpExporter::MAX_DATAGRAM
+                ),
+            )
+        })?;
+        for mut subsegment in std::mem::take(subsegments) {
+            subsegment.independent(trace_id, parent_id);
+            self.export(subsegment.into())?;
+        }
+        self.export(document)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::types::Segment;
+    use serde_json::Value;
+
+    /// Receives a datagram, checking its header and decoding its document
+    fn receive(daemon: &UdpSocket) -> io::Result<Value> {
+        let mut buf = [0; 65_536];
+        let len = daemon.recv(&mut buf)?;
+        let message = std::str::from_utf8(&buf[..len]).expect("utf8 message");
+        let (header, body) = message.split_at(message.find('\n').expect("header line") + 1);
+        assert_eq!(header, "{\"format\": \"json\", \"version\": 1}\n");
+        Ok(serde_json::from_str(body)?)
+    }
+
+    #[test]
+    fn sends_header_and_segment_to_daemon() -> io::Result<()> {
+        let daemon = UdpSocket::bind("127.0.0.1:0")?;
+        let exporter = UdpExporter::with_address(daemon.local_addr()?)?;
+        exporter.export(Segment::begin("test").into())?;
+        assert_eq!(receive(&daemon)?["name"], "test");
+        Ok(())
+    }
+
+    #[test]
+    fn splits_large_documents_into_independent_subsegments() -> io::Result<()> {
+        let daemon = UdpSocket::bind("127.0.0.1:0")?;
+        let exporter = UdpExporter::with_address(daemon.local_addr()?)?;
+        let mut segment = Segment::begin("test");
+        segment.in_progress = true;
+        for name in &["first", "second", "third"] {
+            let mut subsegment = Subsegment::begin(*name);
+            subsegment.end();
+            let mut document = Document::from(subsegment);
+            document.add_metadata("default", "payload", "x".repeat(30_000).into());
+            if let Document::Subsegment(subsegment) = document {
+                segment.subsegments.push(subsegment);
+            }
+        }
+        let (trace_id, id) = (segment.trace_id.to_string(), segment.id.to_string());
+        exporter.export(segment.into())?;
+
+        for name in &["first", "second", "third"] {
+            let subsegment = receive(&daemon)?;
+            assert_eq!(subsegment["name"], *name);
+            assert_eq!(subsegment["type"], "subsegment");
+            assert_eq!(subsegment["trace_id"], trace_id);
+            assert_eq!(subsegment["parent_id"], id);
+        }
+        let segment = receive(&daemon)?;
+        assert_eq!(segment["name"], "test");
+        assert_eq!(segment["in_progress"], true);
+        assert!(segment.get("subsegments").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_documents_which_cannot_be_split() -> io::Result<()> {
+        let daemon = UdpSocket::bind("127.0.0.1:0")?;
+        let exporter = UdpExporter::with_address(daemon.local_addr()?)?;
+        let mut document = Document::from(Segment::begin("test"));
+        document.add_metadata("default", "payload", "x".repeat(70_000).into());
+        let error = exporter
+            .export(document)
+            .expect_err("oversized document was sent");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
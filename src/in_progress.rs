@@ -0,0 +1,183 @@
+//! Exporting in-progress segments for long-running root spans
+
+use crate::{
+    exporter::Exporter,
+    types::{ids::SegmentId, types::Segment},
+};
+use std::{
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Segments waiting for their threshold, shared between the layer and the
+/// timer
+struct Pending {
+    state: Mutex<State>,
+    /// Signalled when a segment starts waiting or the timer should shut down
+    changed: Condvar,
+    /// Held while the timer exports, outside of the state lock
+    sending: Mutex<()>,
+    exporter: Arc<dyn Exporter>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The latest in-progress copy of each open segment, along with when it
+    /// is due to be exported
+    segments: HashMap<SegmentId, (Instant, Segment)>,
+    shutdown: bool,
+}
+
+impl Pending {
+    /// Locks the state, which no panic can leave inconsistent, so that a
+    /// panic never spreads to the spans of the application
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Exports an in-progress copy of each segment still open after a threshold,
+/// from a timer thread, so that spans without any activity are streamed too
+pub(crate) struct InProgress {
+    pending: Arc<Pending>,
+    threshold: Duration,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl InProgress {
+    /// Spawns a timer exporting in-progress segments to `exporter`
+    pub(crate) fn spawn(threshold: Duration, exporter: Arc<dyn Exporter>) -> Self {
+        let pending = Arc::new(Pending {
+            state: Mutex::default(),
+            changed: Condvar::new(),
+            sending: Mutex::default(),
+            exporter,
+        });
+        let timer = {
+            let pending = pending.clone();
+            thread::Builder::new()
+                .name("xray-in-progress".into())
+                .spawn(move || wait(&pending))
+                .expect("failed to spawn in-progress timer")
+        };
+        InProgress {
+            pending,
+            threshold,
+            timer: Some(timer),
+        }
+    }
+
+    /// Starts the threshold of a segment which has just begun
+    pub(crate) fn begin(&self, segment: &Segment) {
+        let due = Instant::now() + self.threshold;
+        self.pending
+            .state()
+            .segments
+            .insert(segment.id, (due, segment.to_in_progress()));
+        self.pending.changed.notify_one();
+    }
+
+    /// Replaces the in-progress copy of `segment`, if it has not been exported
+    /// yet
+    pub(crate) fn update(&self, segment: &Segment) {
+        if let Some((_, in_progress)) = self.pending.state().segments.get_mut(&segment.id) {
+            *in_progress = segment.to_in_progress();
+        }
+    }
+
+    /// Stops waiting for a segment which has ended
+    ///
+    /// Once this returns, the in-progress copy is either exported already or
+    /// never will be, so it cannot replace the complete segment.
+    pub(crate) fn end(&self, segment: &Segment) {
+        self.pending.state().segments.remove(&segment.id);
+        // wait out an export of the copy the timer has already taken
+        drop(self.pending.sending.lock());
+    }
+}
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        self.pending.state().shutdown = true;
+        self.pending.changed.notify_all();
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+    }
+}
+
+fn wait(pending: &Pending) {
+    let mut state = pending.state();
+    while !state.shutdown {
+        let now = Instant::now();
+        let due = state
+            .segments
+            .iter()
+            .filter(|(_, (due, _))| *due <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        if !due.is_empty() {
+            let segments = due
+                .iter()
+                .filter_map(|id| state.segments.remove(id))
+                .map(|(_, segment)| segment)
+                .collect::<Vec<_>>();
+            let sending = pending.sending.lock();
+            drop(state);
+            for segment in segments {
+                // a panicking exporter loses the in-progress copy rather than
+                // the timer
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                    pending.exporter.export(segment.into())
+                }));
+            }
+            drop(sending);
+            state = pending.state();
+            continue;
+        }
+        let next = state.segments.values().map(|(due, _)| *due).min();
+        state = match next {
+            Some(next) => {
+                let timeout = next.saturating_duration_since(Instant::now());
+                let (state, _) = pending
+                    .changed
+                    .wait_timeout(state, timeout)
+                    .unwrap_or_else(PoisonError::into_inner);
+                state
+            }
+            None => pending
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn survives_panicking_exporters() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let exporter = move |_| -> std::io::Result<()> {
+            counted.fetch_add(1, Ordering::SeqCst);
+            panic!("exporter bug")
+        };
+        let in_progress = InProgress::spawn(Duration::ZERO, Arc::new(exporter));
+        for name in &["first", "second"] {
+            let segment = Segment::begin(*name);
+            let expected = calls.load(Ordering::SeqCst) + 1;
+            in_progress.begin(&segment);
+            while calls.load(Ordering::SeqCst) < expected {
+                thread::yield_now();
+            }
+            in_progress.end(&segment);
+        }
+    }
+}
@@ -1,78 +1,303 @@
-use serde::{Deserialize, Serialize};
+use config::ContextMissing;
+use in_progress::InProgress;
+use plugins::Plugin;
+use sampling::Sampler;
+use std::{any::TypeId, env, io, sync::Arc};
 use tracing::{
-    span::{Attributes, Id},
-    Subscriber,
+    span::{Attributes, Id, Record},
+    Dispatch, Event, Level, Subscriber,
 };
 use tracing_subscriber::{
     layer::{Context, Layer},
     registry::LookupSpan,
 };
+use visitor::{EventVisitor, FieldVisitor, RootVisitor};
 
-mod types;
+mod background;
+mod builder;
+pub mod config;
+pub mod exporter;
+mod in_progress;
+#[cfg(feature = "tower")]
+pub mod middleware;
+pub mod plugins;
+#[cfg(feature = "reqwest")]
+pub mod reqwest;
+pub mod sampling;
+#[cfg(all(
+    test,
+    any(
+        feature = "centralized-sampling",
+        feature = "metadata-plugins",
+        feature = "reqwest"
+    )
+))]
+mod stand_in;
+pub mod types;
+mod visitor;
+pub use background::{DropPolicy, XRayGuard};
+pub use builder::Builder;
+pub use exporter::{Exporter, UdpExporter};
 use types::{
-    header::Header,
-    ids::{SegmentId, TraceId},
-    time::Seconds,
-    types::Segment,
+    header::{Header, ParseMode, SamplingDecision},
+    types::{Document, Segment, Subsegment},
 };
 
-type Err = Box<dyn std::error::Error + Send + Sync + 'static>;
-
-#[derive(Default)]
-pub struct XRay;
-#[derive(Default, Debug, Serialize, Deserialize)]
-struct SharedData {
-    pub(crate) trace_id: TraceId,
-    ///  A 64-bit identifier for the segment, unique among segments in the same
-    ///  trace, in 16 hexadecimal digits.
-    pub(crate) id: SegmentId,
-    /// The logical name of the service that handled the request, up to 200
-    /// characters. For example, your application's name or domain name. Names
-    /// can contain Unicode letters, numbers, and whitespace, and the following
-    /// symbols: `_`, `.`,`:`, `/`, `%`, `&, `#`, `=`, `+`, `\`, `-`, `@`
-    ///
-    /// A segment's name should match the domain name or logical name of the
-    /// service that generates the segment. However, this is not enforced. Any
-    /// application that has permission to PutTraceSegments can send segments
-    /// with any name.
-    pub(crate) name: String,
-    /// Number that is the time the segment was created, in floating point
-    /// seconds in epoch time.
-    pub(crate) start_time: Seconds,
-    #[serde(flatten)]
-    pub(crate) state: State,
+/// A [`Layer`] which records spans as X-Ray segments
+///
+/// Spans without a parent become segments, and their descendants become
+/// subsegments embedded in them. Segments are handed to an [`Exporter`] once
+/// their span closes. The default layer has no exporter and discards every
+/// segment.
+///
+/// A root span may continue a trace started upstream by recording the incoming
+/// `X-Amzn-Trace-Id` header in a field of the same name:
+///
+/// ```
+/// let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+/// let span = tracing::info_span!("handler", "X-Amzn-Trace-Id" = header);
+/// ```
+///
+/// The `http.method`, `http.url`, `http.client_ip`, `http.user_agent`,
+/// `http.x_forwarded_for`, `http.traced`, `http.status_code` and
+/// `http.content_length` fields fill in the `http` object of a span's segment
+/// or subsegment, and the status code marks it as throttled, errored or
+/// faulted. The `xray.name` field renames a span's segment or subsegment, and
+/// `xray.namespace` marks a subsegment as a call to an `aws` or `remote`
+/// service. Other span fields are
+/// recorded as annotations and metadata, as described by
+/// [`Builder::annotation_prefix`]. `ERROR` events record an exception on the
+/// segment of the span they occur in and mark it as faulted.
+///
+/// Traces which are not sampled, either by the incoming header or by the
+/// layer's [`Sampler`], are never exported.
+///
+/// Within AWS Lambda, which records the segment for each invocation itself,
+/// spans are recorded as subsegments of the invocation's segment instead, as
+/// described by [`Builder::lambda`].
+pub struct XRay {
+    exporter: Option<Arc<dyn Exporter>>,
+    annotation_prefix: Option<String>,
+    log_events: Option<Level>,
+    sampler: Box<dyn Sampler>,
+    service_name: Option<String>,
+    context_missing: ContextMissing,
+    enabled: bool,
+    trace_header_from_env: bool,
+    in_progress: Option<InProgress>,
+    lambda: bool,
+    plugins: Vec<Box<dyn Plugin>>,
+}
+
+/// Namespace the `Self` and `Lineage` fields of an incoming trace header are
+/// recorded under in segment metadata
+const TRACE_HEADER_NAMESPACE: &str = "trace_header";
+
+/// Builds the trace header for a span, through the subscriber the layer was
+/// added to
+///
+/// The layer hands this out from `downcast_raw`, so that [`current_header`]
+/// can find the span's document without knowing the subscriber's type.
+struct WithHeader(fn(&Dispatch, &Id) -> Option<Header>);
+
+impl WithHeader {
+    fn header<S>(dispatch: &Dispatch, id: &Id) -> Option<Header>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let subscriber = dispatch.downcast_ref::<S>()?;
+        let span = subscriber.span(id)?;
+        let mut header = None;
+        for span in span.scope() {
+            let extensions = span.extensions();
+            let (trace_id, id, decision) = match extensions.get::<Document>() {
+                Some(Document::Segment(segment)) => (
+                    segment.trace_id,
+                    segment.id,
+                    Some(segment.sampling_decision),
+                ),
+                Some(Document::Subsegment(subsegment)) => match &subsegment.independent {
+                    Some(independent) => (
+                        independent.trace_id,
+                        subsegment.id,
+                        Some(subsegment.sampling_decision)
+                            .filter(|decision| *decision != SamplingDecision::Unknown),
+                    ),
+                    None => continue,
+                },
+                None => continue,
+            };
+            let header = header.get_or_insert_with(|| {
+                let mut header = Header::new(trace_id);
+                header.with_parent_id(id);
+                header
+            });
+            // the sampling decision is made for the trace's segment, or for the
+            // top-level subsegment in Lambda
+            if let Some(decision) = decision {
+                header.with_sampling_decision(decision);
+                break;
+            }
+        }
+        header
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
-enum State {
-    /// Number that is the time the segment was closed.
-    Done { end_time: Seconds },
-    ///  boolean, set to true instead of specifying an end_time to record that a
-    ///  segment is started, but is not complete. Send an in-progress segment
-    ///  when your application receives a request that will take a long time to
-    ///  serve, to trace the request receipt. When the response is sent, send
-    ///  the complete segment to overwrite the in-progress segment. Only send
-    ///  one complete segment, and one or zero in-progress segments, per
-    ///  request.
-    InProgress { in_progress: bool },
+/// The trace header to send to downstream services from within the current
+/// span
+///
+/// The header carries the span's trace ID, its subsegment (or segment) as the
+/// `Parent`, and the trace's sampling decision. It is `None` when there is no
+/// current span, the span is not being traced, or the current subscriber does
+/// not include an [`XRay`] layer.
+///
+/// ```
+/// use tracing_subscriber::{layer::SubscriberExt, Registry};
+/// use tracing_xray::{current_header, types::header::Header, XRay};
+///
+/// let subscriber = Registry::default().with(XRay::default());
+/// tracing::subscriber::with_default(subscriber, || {
+///     assert!(current_header().is_none());
+///     tracing::info_span!("handler").in_scope(|| {
+///         let header = current_header().expect("span is traced");
+///         // send as the `X-Amzn-Trace-Id` header of a request
+///         let _value = (Header::NAME, header.to_string());
+///     });
+/// });
+/// ```
+pub fn current_header() -> Option<Header> {
+    tracing::Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let with_header = dispatch.downcast_ref::<WithHeader>()?;
+            (with_header.0)(dispatch, id)
+        })
+        .flatten()
+}
+
+/// Reports that instrumentation outside of a trace had nothing to record
+/// `what` in, according to the [`ContextMissing`] strategy of the current
+/// subscriber's layer
+///
+/// Client instrumentation sends requests outside of a trace untouched, as
+/// their span would otherwise become a segment named after the downstream
+/// host, adding a service to the map which does not exist.
+#[cfg(any(feature = "reqwest", feature = "tower"))]
+pub(crate) fn context_missing(what: std::fmt::Arguments) {
+    tracing::dispatcher::get_default(|dispatch| {
+        if let Some(layer) = dispatch.downcast_ref::<XRay>() {
+            if layer.enabled {
+                layer.context_missing.handle(what);
+            }
+        }
+    });
 }
 
-impl Default for State {
+impl Default for XRay {
     fn default() -> Self {
-        State::InProgress { in_progress: true }
+        XRay::builder().build()
     }
 }
 
-#[test]
-fn test_shared_data_representation() -> Result<(), Err> {
-    let mut data = SharedData::default();
-    dbg!(serde_json::to_string(&data)?);
-    data.state = State::Done {
-        end_time: Seconds::now(),
-    };
-    dbg!(serde_json::to_string(&data)?);
-    Ok(())
+impl XRay {
+    /// Creates a layer which sends completed segments to `exporter`
+    pub fn with_exporter<E>(exporter: E) -> Self
+    where
+        E: Exporter,
+    {
+        XRay::builder().exporter(exporter).build()
+    }
+
+    /// Configures a new layer
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Configures a new layer from the environment variables the X-Ray SDKs
+    /// for other languages read, as described by [`Builder::from_env`]
+    pub fn from_env() -> io::Result<Builder> {
+        Builder::from_env()
+    }
+
+    /// Begins a subsegment of the segment AWS Lambda records for the current
+    /// invocation, as identified by the span's trace header field or the
+    /// `_X_AMZN_TRACE_ID` environment variable
+    fn begin_invocation(&self, name: &str, attrs: &Attributes) -> Option<Document> {
+        let mut visitor = RootVisitor::default();
+        attrs.record(&mut visitor);
+        let header = visitor.header.or_else(|| {
+            let header = env::var(config::TRACE_ID).ok()?;
+            Header::parse(&header, ParseMode::Lenient).ok()
+        });
+        match header {
+            Some(Header {
+                trace_id,
+                parent_id: Some(parent_id),
+                sampling_decision,
+                ..
+            }) => {
+                let mut subsegment = Subsegment::begin(name);
+                subsegment.independent(trace_id, parent_id);
+                subsegment.sampling_decision = sampling_decision;
+                Some(subsegment.into())
+            }
+            _ => {
+                self.context_missing.handle(format_args!(
+                    "no trace header for the Lambda invocation in span `{}`",
+                    name
+                ));
+                None
+            }
+        }
+    }
+
+    /// Begins the segment recording a span at the root of a trace
+    ///
+    /// The segment is named after the service, described by the plugins and
+    /// continues any incoming trace header, otherwise asking the sampler
+    /// whether to sample the new trace.
+    fn begin_segment(&self, name: &str, attrs: &Attributes) -> Document {
+        let name = self.service_name.as_deref().unwrap_or(name);
+        let mut segment = Segment::begin(name);
+        for plugin in &self.plugins {
+            plugin.describe(&mut segment);
+        }
+        let mut visitor = RootVisitor::default();
+        attrs.record(&mut visitor);
+        if visitor.header.is_none() && self.trace_header_from_env {
+            visitor.header = env::var(config::TRACE_ID)
+                .ok()
+                .and_then(|header| Header::parse(&header, ParseMode::Lenient).ok());
+        }
+        let mut trace_header = Vec::new();
+        if let Some(mut header) = visitor.header.take() {
+            if let Some(self_id) = header.self_id.take() {
+                trace_header.push(("self", self_id.to_string()));
+            }
+            if let Some(lineage) = header.lineage.take() {
+                trace_header.push(("lineage", lineage.to_string()));
+            }
+            segment.with_header(header);
+        }
+        if let SamplingDecision::Requested | SamplingDecision::Unknown = segment.sampling_decision {
+            segment.sampling_decision = if self.sampler.sample(&visitor.sampling_request(name)) {
+                SamplingDecision::Sampled
+            } else {
+                SamplingDecision::NotSampled
+            };
+        }
+        let mut document = Document::from(segment);
+        for (key, value) in trace_header {
+            document.add_metadata(TRACE_HEADER_NAMESPACE, key, value.into());
+        }
+        document
+    }
+
+    fn record_fields(&self, document: &mut Document, values: &Record) {
+        values.record(&mut FieldVisitor {
+            document,
+            annotation_prefix: self.annotation_prefix.as_deref(),
+        });
+    }
 }
 
 impl<S> Layer<S> for XRay
@@ -80,24 +305,480 @@ where
     S: Subscriber + for<'span> LookupSpan<'span>,
 {
     fn new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
-        if let Some(id) = attrs.metadata().fields().field("X-Amzn-Trace-Id") {
-            let header = id
-                .to_string()
-                .parse::<Header>()
-                .expect("Unstable to parse header");
+        if !self.enabled {
+            return;
         }
         let name = attrs.metadata().name();
-        let mut data = Segment::begin(name);
         let span = ctx.span(id).expect("in new_span but span does not exist");
+        let subsegment = span
+            .parent()
+            .and_then(|parent| Some(parent.extensions().get::<Document>()?.subsegment(name)));
+        let mut data = match subsegment {
+            Some(subsegment) => subsegment.into(),
+            None if self.lambda => match self.begin_invocation(name, attrs) {
+                Some(document) => document,
+                None => return,
+            },
+            // a span whose parent is not recorded is the root of its trace
+            // as far as the layer knows
+            None => self.begin_segment(name, attrs),
+        };
+        self.record_fields(&mut data, &Record::new(attrs.values()));
+        if let (Some(in_progress), Document::Segment(segment)) = (&self.in_progress, &data) {
+            if segment.sampling_decision != SamplingDecision::NotSampled {
+                in_progress.begin(segment);
+            }
+        }
         span.extensions_mut().insert(data);
     }
 
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        let span = ctx.span(id).expect("in on_record but span does not exist");
+        let mut extensions = span.extensions_mut();
+        if let Some(document) = extensions.get_mut::<Document>() {
+            self.record_fields(document, values);
+            if let (Some(in_progress), Document::Segment(segment)) = (&self.in_progress, document) {
+                in_progress.update(segment);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event, ctx: Context<S>) {
+        let level = *event.metadata().level();
+        let log = self.log_events.is_some_and(|max| level <= max);
+        if level != Level::ERROR && !log {
+            return;
+        }
+        let span = match ctx.event_span(event) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        let mut extensions = span.extensions_mut();
+        if let Some(document) = extensions.get_mut::<Document>() {
+            if log {
+                document.append_metadata("tracing", "events", visitor.log_entry(event.metadata()));
+            }
+            if level == Level::ERROR {
+                document.add_exceptions(visitor.exceptions(event.metadata()));
+            }
+        }
+    }
+
     fn on_close(&self, id: Id, ctx: Context<S>) {
         let span = ctx.span(&id).expect("in on_close but span does not exist");
-        let mut ext = span.extensions_mut();
-        let data = ext
-            .get_mut::<Segment>()
-            .expect("span does not have XRay segment");
+        let mut data = match span.extensions_mut().remove::<Document>() {
+            Some(data) => data,
+            None => return,
+        };
         data.end();
+        if let (Some(in_progress), Document::Segment(segment)) = (&self.in_progress, &data) {
+            in_progress.end(segment);
+        }
+        // children close before their parents, so a subsegment can always be
+        // embedded in its parent's document and exported along with it
+        if let (Document::Subsegment(subsegment), Some(parent)) = (&mut data, span.parent()) {
+            let mut extensions = parent.extensions_mut();
+            if let Some(document) = extensions.get_mut::<Document>() {
+                document.embed(std::mem::take(subsegment));
+                return;
+            }
+        }
+        let sampling_decision = match &data {
+            Document::Segment(segment) => segment.sampling_decision,
+            Document::Subsegment(subsegment) => subsegment.sampling_decision,
+        };
+        if sampling_decision == SamplingDecision::NotSampled {
+            return;
+        }
+        if let Some(exporter) = &self.exporter {
+            // there is nowhere to report export failures from within a layer
+            let _ = exporter.export(data);
+            // Lambda may freeze the process as soon as the invocation ends
+            if self.lambda {
+                let _ = exporter.flush();
+            }
+        }
+    }
+
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        match id {
+            id if id == TypeId::of::<Self>() => Some(self as *const _ as *const ()),
+            id if id == TypeId::of::<WithHeader>() => {
+                let with_header: &'static WithHeader = &WithHeader(WithHeader::header::<S>);
+                Some(with_header as *const _ as *const ())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampling::SamplingRequest;
+    use std::{sync::Mutex, time::Duration};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    #[test]
+    fn test_segment_state_representation() -> Result<(), serde_json::Error> {
+        let mut segment = Segment::begin("test");
+        segment.in_progress = true;
+        let value = serde_json::to_value(&segment)?;
+        assert_eq!(value["in_progress"], true);
+        assert!(value.get("end_time").is_none());
+        segment.end();
+        let value = serde_json::to_value(&segment)?;
+        assert!(value.get("in_progress").is_none());
+        assert!(value["end_time"].is_f64());
+        Ok(())
+    }
+
+    fn capture() -> (impl Exporter, Arc<Mutex<Vec<Segment>>>) {
+        let exported = Arc::new(Mutex::new(Vec::new()));
+        let sink = exported.clone();
+        let exporter = move |document: Document| {
+            match document {
+                Document::Segment(segment) => sink.lock().unwrap().push(segment),
+                Document::Subsegment(_) => panic!("unexpected independent subsegment"),
+            }
+            Ok(())
+        };
+        (exporter, exported)
+    }
+
+    #[test]
+    fn exports_segment_on_close() {
+        let (exporter, exported) = capture();
+        let layer = XRay::with_exporter(exporter);
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let span = tracing::info_span!("handler");
+            let _enter = span.enter();
+        });
+        let exported = exported.lock().unwrap();
+        assert_eq!(exported.len(), 1);
+        assert_eq!(exported[0].name, "handler");
+        assert!(exported[0].end_time.is_some());
+        assert!(!exported[0].in_progress);
+    }
+
+    #[test]
+    fn nests_child_spans_as_subsegments() {
+        let (exporter, exported) = capture();
+        let layer = XRay::with_exporter(exporter);
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let root = tracing::info_span!("handler");
+            let _root = root.enter();
+            let child = tracing::info_span!("query");
+            let _child = child.enter();
+            let grandchild = tracing::info_span!("decode");
+            let _grandchild = grandchild.enter();
+        });
+        let exported = exported.lock().unwrap();
+        assert_eq!(exported.len(), 1);
+        let root = &exported[0];
+        assert_eq!(root.parent_id, None);
+        assert_eq!(root.subsegments.len(), 1);
+        let child = &root.subsegments[0];
+        assert_eq!(child.name, "query");
+        assert!(!child.is_independent());
+        assert!(child.end_time.is_some());
+        let grandchild = &child.subsegments[0];
+        assert_eq!(grandchild.name, "decode");
+        assert!(!grandchild.is_independent());
+        assert!(root.subsegments[0].validate().is_ok());
+    }
+
+    #[test]
+    fn continues_trace_from_header_field() {
+        let (exporter, exported) = capture();
+        let layer = XRay::with_exporter(exporter);
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;\
+                          Sampled=1;Self=1-67891234-12456789abcdef0123456789;Lineage=a87bd80c:1";
+            tracing::info_span!("handler", "X-Amzn-Trace-Id" = header).in_scope(|| {});
+            let header = String::from("Root=1-5759e988-bd862e3fe1be46a994272794;Sampled=0");
+            tracing::info_span!("handler", "X-Amzn-Trace-Id" = %header).in_scope(|| {});
+        });
+        let exported = exported.lock().unwrap();
+        assert_eq!(exported.len(), 1, "unsampled trace was exported");
+        assert_eq!(
+            exported[0].trace_id,
+            "1-5759e988-bd862e3fe1be46a994272793".parse().unwrap()
+        );
+        assert_eq!(
+            exported[0].parent_id,
+            Some("53995c3f42cd8ad8".parse().unwrap())
+        );
+        assert_eq!(exported[0].sampling_decision, SamplingDecision::Sampled);
+        let metadata = exported[0].metadata.as_ref().unwrap();
+        assert_eq!(
+            metadata[TRACE_HEADER_NAMESPACE],
+            serde_json::json!({
+                "self": "1-67891234-12456789abcdef0123456789",
+                "lineage": "a87bd80c:1",
+            })
+        );
+    }
+
+    #[test]
+    fn samples_traces_without_a_decision() {
+        let (exporter, exported) = capture();
+        let layer = XRay::builder()
+            .exporter(exporter)
+            .sampler(|request: &SamplingRequest| request.url_path == Some("/orders"))
+            .build();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info_span!("health", http.url = "https://example.com/health").in_scope(|| {});
+            tracing::info_span!("orders", http.url = "https://example.com/orders").in_scope(|| {});
+            let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=?";
+            tracing::info_span!("requested", "X-Amzn-Trace-Id" = header).in_scope(|| {});
+            let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1";
+            tracing::info_span!("upstream", "X-Amzn-Trace-Id" = header).in_scope(|| {});
+        });
+        let exported = exported.lock().unwrap();
+        let names = exported
+            .iter()
+            .map(|segment| &segment.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["orders", "upstream"]);
+    }
+
+    #[test]
+    fn streams_in_progress_segments() {
+        let (exporter, exported) = capture();
+        let layer = XRay::builder()
+            .exporter(exporter)
+            .sampler(|_: &SamplingRequest| true)
+            .stream_in_progress(Duration::from_millis(20))
+            .build();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info_span!("quick").in_scope(|| {});
+            // nothing happens within the span while it waits
+            tracing::info_span!("poll", http.method = "GET").in_scope(|| {
+                std::thread::sleep(Duration::from_millis(50));
+            });
+        });
+        let exported = exported.lock().unwrap();
+        let states = exported
+            .iter()
+            .map(|segment| (segment.name.as_str(), segment.in_progress))
+            .collect::<Vec<_>>();
+        assert_eq!(states, [("quick", false), ("poll", true), ("poll", false)]);
+        assert_eq!(exported[1].id, exported[2].id);
+        assert!(exported[1].end_time.is_none());
+        let http = exported[1]
+            .http
+            .as_ref()
+            .and_then(|http| http.request.as_ref());
+        assert_eq!(
+            http.and_then(|request| request.method.as_deref()),
+            Some("GET")
+        );
+    }
+
+    #[test]
+    fn builds_header_for_current_span() {
+        let (exporter, exported) = capture();
+        let layer = XRay::with_exporter(exporter);
+        let headers = tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1";
+            tracing::info_span!("handler", "X-Amzn-Trace-Id" = header).in_scope(|| {
+                let outer = current_header().unwrap();
+                let inner = tracing::info_span!("query").in_scope(|| current_header().unwrap());
+                (outer, inner)
+            })
+        });
+        let exported = exported.lock().unwrap();
+        let (outer, inner) = headers;
+        assert_eq!(outer.trace_id, exported[0].trace_id);
+        assert_eq!(outer.parent_id, Some(exported[0].id));
+        assert_eq!(inner.trace_id, exported[0].trace_id);
+        assert_eq!(inner.parent_id, Some(exported[0].subsegments[0].id));
+        assert_eq!(inner.sampling_decision, SamplingDecision::Sampled);
+        assert_eq!(
+            inner.to_string(),
+            format!(
+                "Root=1-5759e988-bd862e3fe1be46a994272793;Parent={};Sampled=1",
+                exported[0].subsegments[0].id
+            )
+        );
+        assert!(current_header().is_none());
+    }
+
+    #[test]
+    fn records_lambda_invocations_as_subsegments() {
+        let exported = Arc::new(Mutex::new(Vec::new()));
+        let sink = exported.clone();
+        let layer = XRay::builder()
+            .lambda(true)
+            .context_missing(ContextMissing::IgnoreError)
+            .exporter(move |document| {
+                sink.lock().unwrap().push(document);
+                Ok(())
+            })
+            .build();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let header =
+                "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+            tracing::info_span!("invocation", "X-Amzn-Trace-Id" = header).in_scope(|| {
+                tracing::info_span!("query").in_scope(|| {});
+                let header = current_header().unwrap();
+                assert_eq!(header.sampling_decision, SamplingDecision::Sampled);
+            });
+            let header =
+                "Root=1-5759e988-bd862e3fe1be46a994272794;Parent=53995c3f42cd8ad8;Sampled=0";
+            tracing::info_span!("invocation", "X-Amzn-Trace-Id" = header).in_scope(|| {});
+            if env::var(config::TRACE_ID).is_err() {
+                tracing::info_span!("untraced").in_scope(|| {});
+            }
+        });
+        let exported = exported.lock().unwrap();
+        assert_eq!(
+            exported.len(),
+            1,
+            "unsampled or untraced invocation was exported"
+        );
+        let subsegment = serde_json::to_value(&exported[0]).unwrap();
+        assert_eq!(subsegment["type"], "subsegment");
+        assert_eq!(subsegment["name"], "invocation");
+        assert_eq!(
+            subsegment["trace_id"],
+            "1-5759e988-bd862e3fe1be46a994272793"
+        );
+        assert_eq!(subsegment["parent_id"], "53995c3f42cd8ad8");
+        assert_eq!(subsegment["subsegments"][0]["name"], "query");
+    }
+
+    #[test]
+    fn records_fields_as_annotations_and_metadata() {
+        let (exporter, exported) = capture();
+        let layer = XRay::with_exporter(exporter);
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let span = tracing::info_span!(
+                "handler",
+                user.id = 42,
+                admin = true,
+                path = "/health",
+                tags = ?["a", "b"],
+                status = tracing::field::Empty
+            );
+            span.record("status", 200);
+        });
+        let exported = exported.lock().unwrap();
+        let value = serde_json::to_value(&exported[0]).expect("failed to serialize");
+        assert_eq!(value["annotations"]["user_id"], 42);
+        assert_eq!(value["annotations"]["admin"], true);
+        assert_eq!(value["annotations"]["path"], "/health");
+        assert_eq!(value["annotations"]["status"], 200);
+        assert_eq!(value["metadata"]["default"]["tags"], r#"["a", "b"]"#);
+    }
+
+    #[test]
+    fn records_prefixed_fields_as_annotations() {
+        let (exporter, exported) = capture();
+        let layer = XRay::builder()
+            .exporter(exporter)
+            .annotation_prefix("xray.annotation.")
+            .build();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info_span!("handler", xray.annotation.tenant = "acme", user.id = 42)
+                .in_scope(|| {});
+        });
+        let exported = exported.lock().unwrap();
+        let value = serde_json::to_value(&exported[0]).expect("failed to serialize");
+        assert_eq!(value["annotations"]["tenant"], "acme");
+        assert!(value["annotations"].get("user_id").is_none());
+        assert_eq!(value["metadata"]["default"]["user.id"], 42);
+    }
+
+    #[derive(Debug)]
+    struct QueryError(std::io::Error);
+
+    impl std::fmt::Display for QueryError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("query failed")
+        }
+    }
+
+    impl std::error::Error for QueryError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn records_error_events_as_exceptions() {
+        let (exporter, exported) = capture();
+        let layer = XRay::with_exporter(exporter);
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info_span!("handler").in_scope(|| {
+                tracing::info!("not recorded");
+                tracing::error!("handler failed");
+                let error = QueryError(std::io::Error::other("connection reset"));
+                tracing::error!(error = &error as &dyn std::error::Error);
+                tracing::error!(cause = &error as &dyn std::error::Error, "lookup failed");
+            });
+        });
+        let exported = exported.lock().unwrap();
+        let value = serde_json::to_value(&exported[0]).expect("failed to serialize");
+        assert_eq!(value["fault"], true);
+        assert!(value.get("metadata").is_none());
+        let exceptions = value["cause"]["exceptions"].as_array().expect("exceptions");
+        assert_eq!(exceptions.len(), 6);
+        assert_eq!(exceptions[0]["message"], "handler failed");
+        assert_eq!(exceptions[0]["stack"][0]["path"], file!());
+        assert_eq!(exceptions[1]["message"], "query failed");
+        assert_eq!(exceptions[1]["type"], "error");
+        assert_eq!(exceptions[1]["cause"], exceptions[2]["id"]);
+        assert_eq!(exceptions[2]["message"], "connection reset");
+        assert!(exceptions[2].get("type").is_none());
+        // the message of an event recording an error is kept, caused by it
+        assert_eq!(exceptions[3]["message"], "lookup failed");
+        assert!(exceptions[3].get("type").is_none());
+        assert_eq!(exceptions[3]["cause"], exceptions[4]["id"]);
+        assert_eq!(exceptions[4]["message"], "query failed");
+        assert_eq!(exceptions[4]["type"], "cause");
+        assert_eq!(exceptions[4]["cause"], exceptions[5]["id"]);
+    }
+
+    #[test]
+    fn ignores_error_events_outside_spans() {
+        let (exporter, exported) = capture();
+        let layer = XRay::builder()
+            .exporter(exporter)
+            .context_missing(ContextMissing::RuntimeError)
+            .build();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::error!("startup failed");
+        });
+        assert!(exported.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn logs_events_when_enabled() {
+        let (exporter, exported) = capture();
+        let layer = XRay::builder()
+            .exporter(exporter)
+            .log_events(Level::INFO)
+            .build();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info_span!("handler").in_scope(|| {
+                tracing::debug!("not recorded");
+                tracing::info!(attempt = 2, "retrying");
+            });
+        });
+        let exported = exported.lock().unwrap();
+        let value = serde_json::to_value(&exported[0]).expect("failed to serialize");
+        let events = value["metadata"]["tracing"]["events"]
+            .as_array()
+            .expect("events");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["level"], "INFO");
+        assert_eq!(events[0]["message"], "retrying");
+        assert_eq!(events[0]["fields"]["attempt"], 2);
+        assert!(events[0]["timestamp"].is_f64());
+        assert!(value.get("cause").is_none());
     }
 }
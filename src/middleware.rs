@@ -0,0 +1,411 @@
+//! [Tower](https://docs.rs/tower) middleware which traces incoming and
+//! outgoing HTTP requests
+
+use crate::{current_header, types::header::Header};
+use http::{header, Request, Response};
+use pin_project_lite::pin_project;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::{field, Span};
+
+/// A [`Layer`] which records every request a service handles as a segment
+///
+/// Each request is handled within a new root span named `http.request`,
+/// which continues the trace of any incoming `X-Amzn-Trace-Id` header and
+/// records the request and its response in the segment's `http` object. The
+/// response status marks the segment as throttled, errored or faulted, and the
+/// trace ID and sampling decision are echoed back in the response's
+/// `X-Amzn-Trace-Id` header.
+///
+/// Requests are only recorded by an [`XRay`](crate::XRay) layer in the current
+/// subscriber, whose [`service_name`](crate::Builder::service_name) names the
+/// segments.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XRayLayer {
+    _priv: (),
+}
+
+impl XRayLayer {
+    /// Creates a layer which traces requests
+    pub fn new() -> Self {
+        XRayLayer::default()
+    }
+}
+
+impl<S> Layer<S> for XRayLayer {
+    type Service = XRayService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        XRayService { inner }
+    }
+}
+
+/// A [`Service`] which records every request it handles as a segment, as
+/// described by [`XRayLayer`]
+#[derive(Clone, Debug)]
+pub struct XRayService<S> {
+    inner: S,
+}
+
+impl<S> XRayService<S> {
+    /// Wraps `inner` to trace the requests it handles
+    pub fn new(inner: S) -> Self {
+        XRayService { inner }
+    }
+}
+
+impl<S, B, C> Service<Request<B>> for XRayService<S>
+where
+    S: Service<Request<B>, Response = Response<C>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let description = crate::types::types::Request::from(&request);
+        let headers = request.headers();
+        let header_str = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let span = tracing::info_span!(
+            parent: None,
+            "http.request",
+            "X-Amzn-Trace-Id" = header_str(Header::NAME),
+            http.method = description.method.as_deref(),
+            http.url = description.url.as_deref(),
+            http.host = header_str(header::HOST.as_str()),
+            http.client_ip = description.client_ip.as_deref(),
+            http.user_agent = description.user_agent.as_deref(),
+            http.x_forwarded_for = description.x_forwarded_for,
+            http.status_code = field::Empty,
+            http.content_length = field::Empty,
+        );
+        let inner = span.in_scope(|| self.inner.call(request));
+        ResponseFuture {
+            inner,
+            span,
+            echo_header: true,
+        }
+    }
+}
+
+/// A [`Layer`] which records every request a client sends as a remote
+/// subsegment
+///
+/// Each request is sent within a new span named `http.client`, a child of the
+/// current span, whose subsegment is named after the host the request is sent
+/// to. An `X-Amzn-Trace-Id` header naming the subsegment as the `Parent` is
+/// added to the request, so the downstream service continues the trace, and
+/// the response status marks the subsegment as throttled, errored or faulted.
+///
+/// Requests sent outside of a traced span are passed on untouched, and
+/// reported according to the layer's
+/// [`context_missing`](crate::Builder::context_missing) strategy. Any client
+/// which is a [`Service`] accepting `http` requests may be wrapped, such as
+/// hyper's legacy `Client`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XRayClientLayer {
+    _priv: (),
+}
+
+impl XRayClientLayer {
+    /// Creates a layer which traces outgoing requests
+    pub fn new() -> Self {
+        XRayClientLayer::default()
+    }
+}
+
+impl<S> Layer<S> for XRayClientLayer {
+    type Service = XRayClient<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        XRayClient { inner }
+    }
+}
+
+/// A client [`Service`] which records every request it sends as a remote
+/// subsegment, as described by [`XRayClientLayer`]
+#[derive(Clone, Debug)]
+pub struct XRayClient<S> {
+    inner: S,
+}
+
+impl<S> XRayClient<S> {
+    /// Wraps `inner` to trace the requests it sends
+    pub fn new(inner: S) -> Self {
+        XRayClient { inner }
+    }
+}
+
+impl<S, B, C> Service<Request<B>> for XRayClient<S>
+where
+    S: Service<Request<B>, Response = Response<C>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        if current_header().is_none() {
+            crate::context_missing(format_args!(
+                "request to `{}` is not within a traced span",
+                request.uri()
+            ));
+            return ResponseFuture {
+                inner: self.inner.call(request),
+                span: Span::none(),
+                echo_header: false,
+            };
+        }
+        let description = crate::types::types::Request::from(&request);
+        let host = request.uri().host().map(String::from).or_else(|| {
+            let host = request.headers().get(header::HOST)?.to_str().ok()?;
+            Some(host.into())
+        });
+        let span = tracing::info_span!(
+            "http.client",
+            xray.name = host.as_deref(),
+            xray.namespace = "remote",
+            http.method = description.method.as_deref(),
+            http.url = description.url.as_deref(),
+            http.traced = true,
+            http.status_code = field::Empty,
+            http.content_length = field::Empty,
+        );
+        if let Some(header) = span.in_scope(current_header) {
+            header.inject(request.headers_mut());
+        }
+        let inner = span.in_scope(|| self.inner.call(request));
+        ResponseFuture {
+            inner,
+            span,
+            echo_header: false,
+        }
+    }
+}
+
+pin_project! {
+    /// Response future of [`XRayService`] and [`XRayClient`]
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: F,
+        span: Span,
+        echo_header: bool,
+    }
+}
+
+impl<F, C, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<C>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut result = {
+            let _enter = this.span.enter();
+            ready!(this.inner.poll(cx))
+        };
+        // the (sub)segment ends as soon as the response is ready, rather than
+        // when this future is dropped
+        let span = std::mem::replace(this.span, Span::none());
+        match &mut result {
+            Ok(response) => {
+                let description = crate::types::types::Response::from(&*response);
+                span.record("http.status_code", description.status);
+                span.record("http.content_length", description.content_length);
+                if *this.echo_header {
+                    if let Some(mut header) = span.in_scope(current_header) {
+                        header.parent_id = None;
+                        header.inject(response.headers_mut());
+                    }
+                }
+            }
+            Err(_) => tracing::error!(parent: &span, "service failed to respond"),
+        }
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::ContextMissing,
+        types::types::{Document, Namespace, Segment},
+        XRay,
+    };
+    use std::{
+        convert::Infallible,
+        future::{ready, Ready},
+        sync::{Arc, Mutex},
+        task::{Wake, Waker},
+    };
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    /// Responds to every request with the status in its `status` header, and
+    /// any trace header it received in a `received` header
+    struct Respond;
+
+    impl Service<Request<()>> for Respond {
+        type Response = Response<&'static str>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<()>) -> Self::Future {
+            let headers = request.headers();
+            let mut response = Response::builder()
+                .status(headers["status"].to_str().unwrap())
+                .header("content-length", "5");
+            if let Some(header) = headers.get(Header::NAME) {
+                response = response.header("received", header);
+            }
+            ready(Ok(response.body("hello").unwrap()))
+        }
+    }
+
+    fn call<S>(service: &mut S, status: &str) -> Response<&'static str>
+    where
+        S: Service<Request<()>, Response = Response<&'static str>, Error = Infallible>,
+    {
+        let request = Request::get("/orders")
+            .header("host", "example.com")
+            .header("status", status)
+            .header(
+                Header::NAME,
+                "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1",
+            )
+            .body(())
+            .unwrap();
+        send(service, request)
+    }
+
+    /// Wakes nothing, as every service in these tests is ready at once
+    struct Noop;
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    fn send<S>(service: &mut S, request: Request<()>) -> Response<&'static str>
+    where
+        S: Service<Request<()>, Response = Response<&'static str>, Error = Infallible>,
+    {
+        let future = std::pin::pin!(service.call(request));
+        let waker = Waker::from(Arc::new(Noop));
+        match future.poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(Ok(response)) => response,
+            _ => panic!("service did not respond"),
+        }
+    }
+
+    fn capture() -> (XRay, Arc<Mutex<Vec<Segment>>>) {
+        let exported = Arc::new(Mutex::new(Vec::<Segment>::new()));
+        let sink = exported.clone();
+        let layer = XRay::builder()
+            .service_name("orders")
+            .exporter(move |document| {
+                if let Document::Segment(segment) = document {
+                    sink.lock().unwrap().push(segment);
+                }
+                Ok(())
+            })
+            .build();
+        (layer, exported)
+    }
+
+    #[test]
+    fn records_requests_as_segments() {
+        let (layer, exported) = capture();
+        let responses = tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let mut service = XRayLayer::new().layer(Respond);
+            (call(&mut service, "200"), call(&mut service, "429"))
+        });
+        assert_eq!(
+            responses.0.headers()[Header::NAME],
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1"
+        );
+        let exported = exported.lock().unwrap();
+        assert_eq!(exported.len(), 2);
+        let segment = serde_json::to_value(&exported[0]).unwrap();
+        assert_eq!(segment["name"], "orders");
+        assert_eq!(segment["parent_id"], "53995c3f42cd8ad8");
+        assert!(segment.get("annotations").is_none());
+        assert_eq!(
+            segment["http"],
+            serde_json::json!({
+                "request": {"method": "GET", "url": "http://example.com/orders"},
+                "response": {"status": 200, "content_length": 5},
+            })
+        );
+        assert!(!exported[0].error && !exported[0].throttle);
+        assert!(exported[1].error && exported[1].throttle && !exported[1].fault);
+    }
+
+    #[test]
+    fn records_outgoing_requests_as_remote_subsegments() {
+        let (layer, exported) = capture();
+        let response = tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let mut server = XRayLayer::new().layer(XRayClientLayer::new().layer(Respond));
+            let response = call(&mut server, "503");
+            let mut client = XRayClientLayer::new().layer(Respond);
+            let untraced = send(
+                &mut client,
+                Request::get("http://example.com/")
+                    .header("status", "200")
+                    .body(())
+                    .unwrap(),
+            );
+            assert!(!untraced.headers().contains_key("received"));
+            response
+        });
+        let exported = exported.lock().unwrap();
+        let subsegment = &exported[0].subsegments[0];
+        assert_eq!(
+            serde_json::to_value(subsegment).unwrap()["http"],
+            serde_json::json!({
+                "request": {"method": "GET", "url": "http://example.com/orders", "traced": true},
+                "response": {"status": 503, "content_length": 5},
+            })
+        );
+        assert_eq!(subsegment.name, "example.com");
+        assert_eq!(subsegment.namespace, Some(Namespace::Remote));
+        assert!(subsegment.fault);
+        assert_eq!(exported.len(), 1);
+        assert_eq!(
+            response.headers()["received"],
+            format!(
+                "Root=1-5759e988-bd862e3fe1be46a994272793;Parent={};Sampled=1",
+                subsegment.id
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "X-Ray context missing")]
+    fn reports_requests_outside_of_a_trace() {
+        let layer = XRay::builder()
+            .context_missing(ContextMissing::RuntimeError)
+            .build();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let mut client = XRayClientLayer::new().layer(Respond);
+            call(&mut client, "200");
+        });
+    }
+}
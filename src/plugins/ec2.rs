@@ -0,0 +1,134 @@
+use super::Plugin;
+use crate::types::types::{Aws, Ec2, Segment};
+use serde::Deserialize;
+use std::{io, time::Duration};
+
+/// Describes the EC2 instance the application runs on, as reported by the
+/// instance metadata service
+///
+/// The metadata service is queried with an IMDSv2 session token, once, when
+/// the plugin is created.
+///
+/// ```no_run
+/// use tracing_xray::{plugins::Ec2Plugin, XRay};
+///
+/// let layer = XRay::builder().plugin(Ec2Plugin::detect()?).build();
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Ec2Plugin {
+    ec2: Ec2,
+}
+
+/// The parts of the instance identity document recorded on segments
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IdentityDocument {
+    instance_id: String,
+    availability_zone: String,
+}
+
+impl Ec2Plugin {
+    /// Origin of segments recorded on an EC2 instance
+    pub const ORIGIN: &'static str = "AWS::EC2::Instance";
+    /// Address of the instance metadata service
+    pub const DEFAULT_BASE_URL: &'static str = "http://169.254.169.254";
+
+    /// Queries the instance metadata service at its usual address
+    ///
+    /// Fails within a second or so when not running on EC2.
+    pub fn detect() -> io::Result<Self> {
+        Ec2Plugin::detect_at(Ec2Plugin::DEFAULT_BASE_URL)
+    }
+
+    /// Queries the instance metadata service at `base_url`
+    pub fn detect_at(base_url: &str) -> io::Result<Self> {
+        let base_url = base_url.trim_end_matches('/');
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(1))
+            .build();
+        let token = agent
+            .put(&format!("{}/latest/api/token", base_url))
+            .set("X-aws-ec2-metadata-token-ttl-seconds", "60")
+            .call()
+            .map_err(io::Error::other)?
+            .into_string()?;
+        let document = agent
+            .get(&format!(
+                "{}/latest/dynamic/instance-identity/document",
+                base_url
+            ))
+            .set("X-aws-ec2-metadata-token", &token)
+            .call()
+            .map_err(io::Error::other)?;
+        let document: IdentityDocument = serde_json::from_reader(document.into_reader())?;
+        Ok(Ec2Plugin {
+            ec2: Ec2 {
+                instance_id: Some(document.instance_id),
+                availability_zone: Some(document.availability_zone),
+            },
+        })
+    }
+
+    /// The instance the application runs on
+    pub fn ec2(&self) -> &Ec2 {
+        &self.ec2
+    }
+}
+
+impl Plugin for Ec2Plugin {
+    fn describe(&self, segment: &mut Segment) {
+        segment
+            .origin
+            .get_or_insert_with(|| Ec2Plugin::ORIGIN.into());
+        segment.aws.get_or_insert_with(Aws::default).ec2 = Some(self.ec2.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stand_in;
+    use serde_json::json;
+
+    #[test]
+    fn describes_instance_from_metadata() -> io::Result<()> {
+        let imds =
+            stand_in::serve(
+                |request| match (request.method.as_str(), request.path.as_str()) {
+                    ("PUT", "/latest/api/token") => (200, "session".into()),
+                    ("GET", "/latest/dynamic/instance-identity/document")
+                        if request.header("X-aws-ec2-metadata-token") == Some("session") =>
+                    {
+                        let document = json!({
+                            "instanceId": "i-0123456789abcdef0",
+                            "availabilityZone": "us-east-1a",
+                            "region": "us-east-1",
+                        });
+                        (200, document.to_string())
+                    }
+                    _ => (401, String::new()),
+                },
+            );
+        let plugin = Ec2Plugin::detect_at(&imds.endpoint)?;
+        let token = imds.requests.recv().unwrap();
+        assert!(token
+            .header("X-aws-ec2-metadata-token-ttl-seconds")
+            .is_some());
+        let mut segment = Segment::begin("orders");
+        plugin.describe(&mut segment);
+        let segment = serde_json::to_value(&segment)?;
+        assert_eq!(segment["origin"], "AWS::EC2::Instance");
+        assert_eq!(
+            segment["aws"]["ec2"],
+            json!({"instance_id": "i-0123456789abcdef0", "availability_zone": "us-east-1a"})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fails_without_metadata_service() {
+        let imds = stand_in::serve(|_| (404, String::new()));
+        assert!(Ec2Plugin::detect_at(&imds.endpoint).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use super::Plugin;
+use crate::{
+    config,
+    types::types::{Aws, CloudwatchLogs, Ecs, Segment},
+};
+use serde::Deserialize;
+use std::{collections::HashMap, env, io, time::Duration};
+
+/// Describes the Amazon ECS container the application runs in, as reported by
+/// the task metadata endpoint
+///
+/// The endpoint is queried once, when the plugin is created. Besides the
+/// container and its task, segments record the CloudWatch Logs log group the
+/// container writes to when it uses the `awslogs` log driver.
+///
+/// ```no_run
+/// use tracing_xray::{plugins::EcsPlugin, XRay};
+///
+/// let layer = XRay::builder().plugin(EcsPlugin::detect()?).build();
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct EcsPlugin {
+    ecs: Ecs,
+    cloudwatch_logs: Vec<CloudwatchLogs>,
+}
+
+/// The parts of the container metadata recorded on segments
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerMetadata {
+    docker_id: Option<String>,
+    #[serde(rename = "ContainerARN")]
+    container_arn: Option<String>,
+    log_driver: Option<String>,
+    #[serde(default)]
+    log_options: HashMap<String, String>,
+}
+
+/// The parts of the task metadata recorded on segments
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TaskMetadata {
+    cluster: Option<String>,
+    #[serde(rename = "TaskARN")]
+    task_arn: Option<String>,
+    family: Option<String>,
+    launch_type: Option<String>,
+}
+
+impl EcsPlugin {
+    /// Origin of segments recorded in an ECS container
+    pub const ORIGIN: &'static str = "AWS::ECS::Container";
+
+    /// Queries the metadata endpoint ECS names in the
+    /// `ECS_CONTAINER_METADATA_URI_V4` environment variable
+    ///
+    /// Fails with [`io::ErrorKind::NotFound`] when the variable is not set,
+    /// as outside of ECS.
+    pub fn detect() -> io::Result<Self> {
+        let uri = env::var(config::ECS_CONTAINER_METADATA_URI).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not set", config::ECS_CONTAINER_METADATA_URI),
+            )
+        })?;
+        EcsPlugin::detect_at(&uri)
+    }
+
+    /// Queries the container metadata endpoint at `uri`
+    ///
+    /// The container's hostname is read from the `HOSTNAME` environment
+    /// variable, which Docker sets to it.
+    pub fn detect_at(uri: &str) -> io::Result<Self> {
+        let uri = uri.trim_end_matches('/');
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(1))
+            .build();
+        let get = |uri: &str| agent.get(uri).call().map_err(io::Error::other);
+        let container: ContainerMetadata = serde_json::from_reader(get(uri)?.into_reader())?;
+        let task: TaskMetadata =
+            serde_json::from_reader(get(&format!("{}/task", uri))?.into_reader())?;
+        let cloudwatch_logs = match (
+            container.log_driver.as_deref(),
+            container.log_options.get("awslogs-group"),
+        ) {
+            (Some("awslogs"), Some(group)) => vec![CloudwatchLogs {
+                log_group: Some(group.clone()),
+                arn: log_group_arn(&container, group),
+            }],
+            _ => Vec::new(),
+        };
+        Ok(EcsPlugin {
+            ecs: Ecs {
+                container: env::var("HOSTNAME").ok(),
+                container_id: container.docker_id,
+                container_arn: container.container_arn,
+                task_arn: task.task_arn,
+                task_family: task.family,
+                cluster: task.cluster,
+                launch_type: task.launch_type,
+            },
+            cloudwatch_logs,
+        })
+    }
+
+    /// The container the application runs in
+    pub fn ecs(&self) -> &Ecs {
+        &self.ecs
+    }
+}
+
+/// The ARN of a log group in the container's partition, account and the
+/// region its logs are sent to
+fn log_group_arn(container: &ContainerMetadata, group: &str) -> Option<String> {
+    let region = container.log_options.get("awslogs-region")?;
+    let mut arn = container.container_arn.as_deref()?.split(':');
+    let partition = arn.nth(1)?;
+    let account = arn.nth(2)?;
+    Some(format!(
+        "arn:{}:logs:{}:{}:log-group:{}",
+        partition, region, account, group
+    ))
+}
+
+impl Plugin for EcsPlugin {
+    fn describe(&self, segment: &mut Segment) {
+        segment
+            .origin
+            .get_or_insert_with(|| EcsPlugin::ORIGIN.into());
+        let aws = segment.aws.get_or_insert_with(Aws::default);
+        aws.ecs = Some(self.ecs.clone());
+        aws.cloudwatch_logs
+            .extend(self.cloudwatch_logs.iter().cloned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stand_in;
+    use serde_json::json;
+
+    #[test]
+    fn describes_container_from_metadata() -> io::Result<()> {
+        let metadata = stand_in::serve(|request| match request.path.as_str() {
+            "/v4/abc" => {
+                let container = json!({
+                    "DockerId": "abc123",
+                    "Name": "orders",
+                    "ContainerARN": "arn:aws:ecs:us-west-2:111122223333:container/abc",
+                    "LogDriver": "awslogs",
+                    "LogOptions": {
+                        "awslogs-group": "/ecs/orders",
+                        "awslogs-region": "us-west-2",
+                        "awslogs-stream": "ecs/orders/abc",
+                    },
+                });
+                (200, container.to_string())
+            }
+            "/v4/abc/task" => {
+                let task = json!({
+                    "Cluster": "arn:aws:ecs:us-west-2:111122223333:cluster/default",
+                    "TaskARN": "arn:aws:ecs:us-west-2:111122223333:task/default/158d1c",
+                    "Family": "orders",
+                    "LaunchType": "FARGATE",
+                });
+                (200, task.to_string())
+            }
+            _ => (404, String::new()),
+        });
+        let plugin = EcsPlugin::detect_at(&format!("{}/v4/abc", metadata.endpoint))?;
+        let mut segment = Segment::begin("orders");
+        plugin.describe(&mut segment);
+        let mut segment = serde_json::to_value(&segment)?;
+        assert_eq!(segment["origin"], "AWS::ECS::Container");
+        let hostname = segment["aws"]["ecs"]
+            .as_object_mut()
+            .and_then(|ecs| ecs.remove("container"));
+        assert_eq!(hostname, env::var("HOSTNAME").ok().map(Into::into));
+        assert_eq!(
+            segment["aws"]["ecs"],
+            json!({
+                "container_id": "abc123",
+                "container_arn": "arn:aws:ecs:us-west-2:111122223333:container/abc",
+                "task_arn": "arn:aws:ecs:us-west-2:111122223333:task/default/158d1c",
+                "task_family": "orders",
+                "cluster": "arn:aws:ecs:us-west-2:111122223333:cluster/default",
+                "launch_type": "FARGATE",
+            })
+        );
+        assert_eq!(
+            segment["aws"]["cloudwatch_logs"],
+            json!([{
+                "log_group": "/ecs/orders",
+                "arn": "arn:aws:logs:us-west-2:111122223333:log-group:/ecs/orders",
+            }])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fails_without_metadata_endpoint() {
+        let metadata = stand_in::serve(|_| (404, String::new()));
+        assert!(EcsPlugin::detect_at(&metadata.endpoint).is_err());
+    }
+}
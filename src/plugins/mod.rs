@@ -0,0 +1,27 @@
+//! Descriptions of the environment an application runs in, recorded on every
+//! segment
+
+use crate::types::types::Segment;
+
+#[cfg(feature = "metadata-plugins")]
+mod ec2;
+#[cfg(feature = "metadata-plugins")]
+mod ecs;
+
+#[cfg(feature = "metadata-plugins")]
+pub use ec2::Ec2Plugin;
+#[cfg(feature = "metadata-plugins")]
+pub use ecs::EcsPlugin;
+
+/// Describes the environment the application runs in on each segment
+///
+/// Plugins detect their environment once, when they are created, so that
+/// describing a segment is cheap.
+pub trait Plugin: Send + Sync + 'static {
+    /// Records the environment on a new segment
+    ///
+    /// A plugin only sets the segment's `origin` if no other plugin has, so
+    /// that the first plugin added to a layer names the most specific
+    /// resource running the application.
+    fn describe(&self, segment: &mut Segment);
+}
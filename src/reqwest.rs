@@ -0,0 +1,148 @@
+//! Tracing of requests sent with [reqwest](https://docs.rs/reqwest)
+
+use crate::current_header;
+use ::reqwest::{RequestBuilder, Response};
+use std::{error::Error, future::Future, pin::Pin};
+use tracing::{field, Instrument};
+
+/// The future returned by [`RequestBuilderExt::send_traced`]
+pub type Pending = Pin<Box<dyn Future<Output = ::reqwest::Result<Response>> + Send>>;
+
+/// Sends reqwest requests as remote subsegments of the current span
+pub trait RequestBuilderExt {
+    /// Sends the request within a new span named `http.client`, a child of
+    /// the current span
+    ///
+    /// The span's subsegment is named after the host the request is sent to,
+    /// and records the request along with the status and length of the
+    /// response, which marks it as throttled, errored or faulted. An
+    /// `X-Amzn-Trace-Id` header naming the subsegment as the `Parent` is added
+    /// to the request, so the downstream service continues the trace.
+    ///
+    /// The subsegment ends once the response headers are received. Requests
+    /// sent outside of a traced span are sent untouched, and reported
+    /// according to the layer's
+    /// [`context_missing`](crate::Builder::context_missing) strategy.
+    fn send_traced(self) -> Pending;
+}
+
+impl RequestBuilderExt for RequestBuilder {
+    fn send_traced(self) -> Pending {
+        let (client, request) = self.build_split();
+        let mut request = match request {
+            Ok(request) => request,
+            Err(error) => return Box::pin(async move { Err(error) }),
+        };
+        if current_header().is_none() {
+            crate::context_missing(format_args!(
+                "request to `{}` is not within a traced span",
+                request.url()
+            ));
+            return Box::pin(client.execute(request));
+        }
+        let url = request.url();
+        let span = tracing::info_span!(
+            "http.client",
+            xray.name = url.host_str(),
+            xray.namespace = "remote",
+            http.method = request.method().as_str(),
+            http.url = url.as_str(),
+            http.traced = true,
+            http.status_code = field::Empty,
+            http.content_length = field::Empty,
+        );
+        if let Some(header) = span.in_scope(current_header) {
+            header.inject(request.headers_mut());
+        }
+        Box::pin(async move {
+            let result = client.execute(request).instrument(span.clone()).await;
+            match &result {
+                Ok(response) => {
+                    span.record("http.status_code", response.status().as_u16());
+                    span.record("http.content_length", response.content_length());
+                }
+                Err(error) => tracing::error!(
+                    parent: &span,
+                    error = error as &(dyn Error + 'static),
+                    "request failed"
+                ),
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sampling::SamplingRequest,
+        stand_in,
+        types::{
+            header::Header,
+            types::{Document, Namespace, Segment},
+        },
+        XRay,
+    };
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    #[test]
+    fn records_requests_as_remote_subsegments() {
+        let exported = Arc::new(Mutex::new(Vec::<Segment>::new()));
+        let sink = exported.clone();
+        let layer = XRay::builder()
+            .sampler(|_: &SamplingRequest| true)
+            .exporter(move |document| {
+                if let Document::Segment(segment) = document {
+                    sink.lock().unwrap().push(segment);
+                }
+                Ok(())
+            })
+            .build();
+        let server = stand_in::serve(|_| (404, "missing".into()));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let span = tracing::info_span!("handler");
+            let send = span.in_scope(|| {
+                ::reqwest::Client::new()
+                    .get(format!("{}/items?id=1", server.endpoint))
+                    .send_traced()
+            });
+            runtime.block_on(send.instrument(span)).unwrap();
+            let untraced = ::reqwest::Client::new()
+                .get(format!("{}/health", server.endpoint))
+                .send_traced();
+            runtime.block_on(untraced).unwrap();
+        });
+        let exported = exported.lock().unwrap();
+        assert_eq!(exported.len(), 1);
+        let subsegment = &exported[0].subsegments[0];
+        assert_eq!(subsegment.name, "127.0.0.1");
+        assert_eq!(subsegment.namespace, Some(Namespace::Remote));
+        assert!(subsegment.error && !subsegment.fault);
+        assert_eq!(
+            serde_json::to_value(subsegment).unwrap()["http"],
+            serde_json::json!({
+                "request": {
+                    "method": "GET",
+                    "url": format!("{}/items?id=1", server.endpoint),
+                    "traced": true,
+                },
+                "response": {"status": 404, "content_length": 7},
+            })
+        );
+        let request = server.requests.recv().unwrap();
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("GET", "/items?id=1")
+        );
+        let header = request.header(Header::NAME).unwrap().parse::<Header>();
+        assert_eq!(header.unwrap().parent_id, Some(subsegment.id));
+        let untraced = server.requests.recv().unwrap();
+        assert!(untraced.header(Header::NAME).is_none());
+    }
+}
@@ -0,0 +1,539 @@
+use super::{local::LocalSampler, wildcard_match, Sampler, SamplingRequest};
+use crate::types::{time::Seconds, types::Bytes};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    thread,
+    time::Duration,
+};
+
+/// How often sampling rules are fetched, in seconds
+const RULES_INTERVAL: u64 = 300;
+/// How long fetched rules are used for without being refreshed, in seconds
+const RULES_TTL: u64 = 3600;
+/// How often statistics are reported when the daemon specifies no interval,
+/// in seconds
+const DEFAULT_TARGETS_INTERVAL: u64 = 10;
+
+/// Samples requests according to rules shared across a fleet of services
+///
+/// Sampling rules are fetched through the X-Ray daemon's `GetSamplingRules`
+/// proxy, and each rule's reservoir is shared among every service applying it
+/// by periodically reporting statistics through `GetSamplingTargets`. Until
+/// rules have been fetched, or when they have not been refreshed within an
+/// hour, requests are sampled by a fallback [`LocalSampler`].
+///
+/// ```no_run
+/// use tracing_xray::sampling::CentralizedSampler;
+///
+/// let sampler = CentralizedSampler::new("http://127.0.0.1:2000").start();
+/// ```
+#[derive(Clone)]
+pub struct CentralizedSampler {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    endpoint: String,
+    client_id: String,
+    agent: ureq::Agent,
+    fallback: LocalSampler,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    rules: Vec<CentralRule>,
+    rules_fetched_at: Option<u64>,
+    rules_due_at: u64,
+    targets_due_at: u64,
+}
+
+impl CentralizedSampler {
+    /// Creates a sampler fetching rules from the daemon's proxy at `endpoint`,
+    /// such as `http://127.0.0.1:2000`
+    ///
+    /// Rules are only fetched once the sampler is [started], or when
+    /// [`refresh_rules`] is called.
+    ///
+    /// [started]: CentralizedSampler::start
+    /// [`refresh_rules`]: CentralizedSampler::refresh_rules
+    pub fn new<E>(endpoint: E) -> Self
+    where
+        E: Into<String>,
+    {
+        let mut client_id = [0; 12];
+        rand::thread_rng().fill_bytes(&mut client_id);
+        CentralizedSampler {
+            shared: Arc::new(Shared {
+                endpoint: endpoint.into().trim_end_matches('/').into(),
+                client_id: format!("{:x}", Bytes(&client_id)),
+                agent: ureq::AgentBuilder::new()
+                    .timeout(Duration::from_secs(2))
+                    .build(),
+                fallback: LocalSampler::default(),
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Samples requests with `fallback` while centralized rules are
+    /// unavailable
+    ///
+    /// # Panics
+    ///
+    /// If the sampler has been started or cloned.
+    pub fn with_fallback(mut self, fallback: LocalSampler) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("fallback must be configured before the sampler is shared")
+            .fallback = fallback;
+        self
+    }
+
+    /// Polls the daemon for rules and targets on a background thread, which
+    /// exits once the sampler is dropped
+    pub fn start(self) -> Self {
+        let shared = Arc::downgrade(&self.shared);
+        thread::Builder::new()
+            .name("xray-sampling".into())
+            .spawn(move || poll(shared))
+            .expect("failed to spawn sampling poller");
+        self
+    }
+
+    /// Fetches the current sampling rules
+    ///
+    /// Reservoirs and statistics of rules which still exist are kept.
+    pub fn refresh_rules(&self) -> io::Result<()> {
+        let mut records = Vec::new();
+        let mut next_token = None;
+        loop {
+            let response: GetSamplingRulesResponse = self.post(
+                "GetSamplingRules",
+                &GetSamplingRulesRequest {
+                    next_token: next_token.take(),
+                },
+            )?;
+            records.extend(response.sampling_rule_records);
+            match response.next_token {
+                Some(token) => next_token = Some(token),
+                None => break,
+            }
+        }
+
+        let now = Seconds::now().trunc();
+        let mut state = self.state();
+        let mut previous = std::mem::take(&mut state.rules)
+            .into_iter()
+            .map(|rule| (rule.definition.rule_name.clone(), rule))
+            .collect::<HashMap<_, _>>();
+        state.rules = records
+            .into_iter()
+            .map(|record| record.sampling_rule)
+            .filter(|rule| rule.version == 1)
+            .map(|definition| match previous.remove(&definition.rule_name) {
+                Some(rule) => CentralRule { definition, ..rule },
+                None => CentralRule::new(definition),
+            })
+            .collect();
+        state.rules.sort_by(|a, b| {
+            (a.definition.priority, &a.definition.rule_name)
+                .cmp(&(b.definition.priority, &b.definition.rule_name))
+        });
+        state.rules_fetched_at = Some(now);
+        state.rules_due_at = now + RULES_INTERVAL;
+        Ok(())
+    }
+
+    /// Reports sampling statistics and fetches each rule's share of its
+    /// reservoir
+    ///
+    /// Does nothing until rules have been fetched.
+    pub fn refresh_targets(&self) -> io::Result<()> {
+        let now = Seconds::now();
+        let statistics = {
+            let mut state = self.state();
+            if state.rules.is_empty() {
+                state.targets_due_at = now.trunc() + DEFAULT_TARGETS_INTERVAL;
+                return Ok(());
+            }
+            state
+                .rules
+                .iter_mut()
+                .map(|rule| SamplingStatisticsDocument {
+                    rule_name: rule.definition.rule_name.clone(),
+                    client_id: self.shared.client_id.clone(),
+                    timestamp: now.trunc(),
+                    request_count: std::mem::take(&mut rule.statistics.requests),
+                    sampled_count: std::mem::take(&mut rule.statistics.sampled),
+                    borrow_count: std::mem::take(&mut rule.statistics.borrowed),
+                })
+                .collect::<Vec<_>>()
+        };
+        let response: GetSamplingTargetsResponse = self.post(
+            "SamplingTargets",
+            &GetSamplingTargetsRequest {
+                sampling_statistics_documents: statistics,
+            },
+        )?;
+
+        let mut state = self.state();
+        let mut interval: Option<u64> = None;
+        for target in response.sampling_target_documents {
+            if let Some(rule) = state
+                .rules
+                .iter_mut()
+                .find(|rule| rule.definition.rule_name == target.rule_name)
+            {
+                if let Some(rate) = target.fixed_rate {
+                    rule.fixed_rate = rate;
+                }
+                if let Some(quota) = target.reservoir_quota {
+                    rule.reservoir.quota = Some(quota);
+                    rule.reservoir.expires_at =
+                        target.reservoir_quota_ttl.map_or(0, |ttl| ttl as u64);
+                }
+            }
+            if let Some(target_interval) = target.interval {
+                interval = Some(interval.map_or(target_interval, |i| i.min(target_interval)));
+            }
+        }
+        state.targets_due_at = now.trunc() + interval.unwrap_or(DEFAULT_TARGETS_INTERVAL);
+        let modified = response.last_rule_modification.map(|at| at as u64);
+        if modified > state.rules_fetched_at {
+            state.rules_due_at = 0;
+        }
+        Ok(())
+    }
+
+    fn post<Req, Resp>(&self, path: &str, request: &Req) -> io::Result<Resp>
+    where
+        Req: Serialize,
+        Resp: for<'de> Deserialize<'de>,
+    {
+        let response = self
+            .shared
+            .agent
+            .post(&format!("{}/{}", self.shared.endpoint, path))
+            .set("Content-Type", "application/json")
+            .send_string(&serde_json::to_string(request)?)
+            .map_err(io::Error::other)?;
+        Ok(serde_json::from_reader(response.into_reader())?)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.shared
+            .state
+            .lock()
+            .expect("sampling state lock poisoned")
+    }
+}
+
+impl Sampler for CentralizedSampler {
+    fn sample(&self, request: &SamplingRequest) -> bool {
+        let now = Seconds::now().trunc();
+        let mut state = self.state();
+        let fresh = state
+            .rules_fetched_at
+            .is_some_and(|fetched| now < fetched + RULES_TTL);
+        let rule = match state.rules.iter_mut().find(|rule| rule.matches(request)) {
+            Some(rule) if fresh => rule,
+            _ => {
+                drop(state);
+                return self.shared.fallback.sample(request);
+            }
+        };
+        rule.statistics.requests += 1;
+        let sampled = match rule.reservoir.take(now, rule.definition.reservoir_size > 0) {
+            Some(Take::Quota) => true,
+            Some(Take::Borrowed) => {
+                rule.statistics.borrowed += 1;
+                true
+            }
+            None => rand::random::<f64>() < rule.fixed_rate,
+        };
+        if sampled {
+            rule.statistics.sampled += 1;
+        }
+        sampled
+    }
+}
+
+/// Refreshes rules and targets as they fall due, until the sampler is dropped
+fn poll(shared: Weak<Shared>) {
+    while let Some(shared) = shared.upgrade() {
+        let sampler = CentralizedSampler { shared };
+        let now = Seconds::now().trunc();
+        let (rules_due, targets_due) = {
+            let state = sampler.state();
+            (state.rules_due_at <= now, state.targets_due_at <= now)
+        };
+        // failures are retried as often as targets are fetched, and requests
+        // are sampled by the fallback until rules are fetched
+        if rules_due && sampler.refresh_rules().is_err() {
+            sampler.state().rules_due_at = now + DEFAULT_TARGETS_INTERVAL;
+        }
+        if targets_due && sampler.refresh_targets().is_err() {
+            sampler.state().targets_due_at = now + DEFAULT_TARGETS_INTERVAL;
+        }
+        drop(sampler);
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+struct CentralRule {
+    definition: SamplingRule,
+    fixed_rate: f64,
+    reservoir: Reservoir,
+    statistics: Statistics,
+}
+
+impl CentralRule {
+    fn new(definition: SamplingRule) -> Self {
+        CentralRule {
+            fixed_rate: definition.fixed_rate,
+            definition,
+            reservoir: Reservoir::default(),
+            statistics: Statistics::default(),
+        }
+    }
+
+    fn matches(&self, request: &SamplingRequest) -> bool {
+        let rule = &self.definition;
+        rule.attributes.is_empty()
+            && wildcard_match(&rule.resource_arn, "*")
+            && wildcard_match(&rule.service_type, "")
+            && wildcard_match(&rule.service_name, request.service_name)
+            && wildcard_match(&rule.host, request.host.unwrap_or_default())
+            && wildcard_match(&rule.http_method, request.http_method.unwrap_or_default())
+            && wildcard_match(&rule.url_path, request.url_path.unwrap_or_default())
+    }
+}
+
+/// A rule's share of its fleet-wide reservoir
+///
+/// Until the daemon assigns a quota, or once it expires, one request each
+/// second is borrowed from the reservoir.
+#[derive(Default)]
+struct Reservoir {
+    quota: Option<u64>,
+    expires_at: u64,
+    second: u64,
+    used: u64,
+}
+
+enum Take {
+    Quota,
+    Borrowed,
+}
+
+impl Reservoir {
+    fn take(&mut self, now: u64, can_borrow: bool) -> Option<Take> {
+        if self.second != now {
+            self.second = now;
+            self.used = 0;
+        }
+        match self.quota {
+            Some(quota) if now < self.expires_at => {
+                if self.used < quota {
+                    self.used += 1;
+                    Some(Take::Quota)
+                } else {
+                    None
+                }
+            }
+            _ if can_borrow && self.used < 1 => {
+                self.used += 1;
+                Some(Take::Borrowed)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Statistics {
+    requests: u64,
+    sampled: u64,
+    borrowed: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSamplingRulesRequest {
+    next_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSamplingRulesResponse {
+    #[serde(default)]
+    sampling_rule_records: Vec<SamplingRuleRecord>,
+    next_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SamplingRuleRecord {
+    sampling_rule: SamplingRule,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SamplingRule {
+    rule_name: String,
+    priority: i32,
+    fixed_rate: f64,
+    reservoir_size: u64,
+    service_name: String,
+    service_type: String,
+    host: String,
+    #[serde(rename = "HTTPMethod")]
+    http_method: String,
+    #[serde(rename = "URLPath")]
+    url_path: String,
+    #[serde(rename = "ResourceARN")]
+    resource_arn: String,
+    version: u32,
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSamplingTargetsRequest {
+    sampling_statistics_documents: Vec<SamplingStatisticsDocument>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SamplingStatisticsDocument {
+    rule_name: String,
+    #[serde(rename = "ClientID")]
+    client_id: String,
+    timestamp: u64,
+    request_count: u64,
+    sampled_count: u64,
+    borrow_count: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSamplingTargetsResponse {
+    #[serde(default)]
+    sampling_target_documents: Vec<SamplingTargetDocument>,
+    last_rule_modification: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SamplingTargetDocument {
+    rule_name: String,
+    fixed_rate: Option<f64>,
+    reservoir_quota: Option<u64>,
+    #[serde(rename = "ReservoirQuotaTTL")]
+    reservoir_quota_ttl: Option<f64>,
+    interval: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sampling::Rule, stand_in};
+    use serde_json::{json, Value};
+
+    fn rule(name: &str, priority: i32, url_path: &str, rate: f64) -> Value {
+        json!({
+            "SamplingRule": {
+                "RuleName": name,
+                "RuleARN": format!("arn:aws:xray:us-east-1:123456789012:sampling-rule/{}", name),
+                "ResourceARN": "*",
+                "Priority": priority,
+                "FixedRate": rate,
+                "ReservoirSize": 0,
+                "ServiceName": "*",
+                "ServiceType": "*",
+                "Host": "*",
+                "HTTPMethod": "*",
+                "URLPath": url_path,
+                "Version": 1,
+                "Attributes": {}
+            }
+        })
+    }
+
+    fn request(url_path: &str) -> SamplingRequest<'_> {
+        SamplingRequest {
+            service_name: "checkout",
+            url_path: Some(url_path),
+            ..SamplingRequest::default()
+        }
+    }
+
+    #[test]
+    fn applies_fetched_rules_by_priority() -> io::Result<()> {
+        let daemon = stand_in::serve(|request| match request.path.as_str() {
+            "/GetSamplingRules" => (
+                200,
+                json!({
+                    "SamplingRuleRecords": [
+                        rule("Default", 10000, "*", 0.0),
+                        rule("Orders", 1, "/orders", 1.0),
+                    ]
+                })
+                .to_string(),
+            ),
+            "/SamplingTargets" => (
+                200,
+                json!({
+                    "SamplingTargetDocuments": [
+                        { "RuleName": "Default", "FixedRate": 1.0, "Interval": 10 }
+                    ],
+                    "UnprocessedStatistics": []
+                })
+                .to_string(),
+            ),
+            _ => (404, String::new()),
+        });
+        let sampler = CentralizedSampler::new(daemon.endpoint);
+        sampler.refresh_rules()?;
+        assert!(sampler.sample(&request("/orders")));
+        assert!(!sampler.sample(&request("/health")));
+
+        sampler.refresh_targets()?;
+        assert!(sampler.sample(&request("/health")));
+
+        daemon.requests.recv().expect("rules request");
+        let statistics = daemon.requests.recv().expect("targets request");
+        assert_eq!(statistics.method, "POST");
+        let statistics: Value = serde_json::from_str(&statistics.body)?;
+        let documents = statistics["SamplingStatisticsDocuments"]
+            .as_array()
+            .expect("statistics");
+        let orders = documents
+            .iter()
+            .find(|document| document["RuleName"] == "Orders")
+            .expect("orders statistics");
+        assert_eq!(orders["RequestCount"], 1);
+        assert_eq!(orders["SampledCount"], 1);
+        assert_eq!(orders["BorrowCount"], 0);
+        assert_eq!(orders["ClientID"].as_str().map(str::len), Some(24));
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_to_local_rules_when_unreachable() {
+        let daemon = stand_in::serve(|_| (500, String::new()));
+        let sampler = CentralizedSampler::new(daemon.endpoint)
+            .with_fallback(LocalSampler::new(Vec::new(), Rule::new(0, 1.0)));
+        assert!(sampler.refresh_rules().is_err());
+        assert!(sampler.sample(&request("/orders")));
+        // without rules there are no statistics to report
+        sampler.refresh_targets().unwrap();
+        daemon.requests.recv().expect("rules request");
+        assert!(daemon.requests.try_recv().is_err());
+    }
+}
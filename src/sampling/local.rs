@@ -0,0 +1,266 @@
+use super::{wildcard_match, Sampler, SamplingRequest};
+use crate::types::time::Seconds;
+use serde::{de, Deserialize, Deserializer};
+use std::{fs, io, path::Path, sync::Mutex};
+
+/// Samples requests according to rules evaluated within this process
+///
+/// Each rule reserves a fixed number of requests per second, and samples a
+/// fixed rate of the requests beyond that. The default sampler matches the
+/// X-Ray SDKs, recording the first request each second and 5% of any
+/// additional requests.
+///
+/// Rules may be loaded from the JSON format the X-Ray SDKs accept:
+///
+/// ```
+/// use tracing_xray::sampling::LocalSampler;
+///
+/// let sampler = LocalSampler::from_json(r#"{
+///     "version": 2,
+///     "rules": [{
+///         "description": "Health checks",
+///         "host": "*",
+///         "http_method": "GET",
+///         "url_path": "/health",
+///         "fixed_target": 0,
+///         "rate": 0.0
+///     }],
+///     "default": { "fixed_target": 1, "rate": 0.1 }
+/// }"#).expect("valid rules");
+/// ```
+#[derive(Debug)]
+pub struct LocalSampler {
+    rules: Vec<Rule>,
+    default: Rule,
+}
+
+impl LocalSampler {
+    /// Creates a sampler which applies the first of `rules` matching a
+    /// request, or `default` if none do
+    pub fn new(rules: Vec<Rule>, default: Rule) -> Self {
+        LocalSampler { rules, default }
+    }
+
+    /// Parses a version 1 or 2 sampling rule document
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        let document = serde_json::from_str::<RuleDocument>(json)?;
+        Ok(LocalSampler::new(document.rules, document.default))
+    }
+
+    /// Reads a version 1 or 2 sampling rule document from a file
+    pub fn from_path<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(LocalSampler::from_json(&fs::read_to_string(path)?)?)
+    }
+
+    /// The rule applied to `request`
+    pub(crate) fn matching(&self, request: &SamplingRequest) -> &Rule {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(request))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl Default for LocalSampler {
+    fn default() -> Self {
+        LocalSampler::new(Vec::new(), Rule::new(1, 0.05))
+    }
+}
+
+impl Sampler for LocalSampler {
+    fn sample(&self, request: &SamplingRequest) -> bool {
+        self.matching(request).sample()
+    }
+}
+
+/// A local sampling rule
+///
+/// Requests match a rule when each of its properties matches, where `*`
+/// matches any number of characters and `?` matches exactly one.
+#[derive(Debug, Deserialize)]
+pub struct Rule {
+    /// A description of the requests the rule applies to
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Matches the name of the service handling the request
+    #[serde(default = "any")]
+    pub service_name: String,
+    /// Matches the `Host` the request was made to
+    #[serde(default = "any")]
+    pub host: String,
+    /// Matches the HTTP method of the request
+    #[serde(default = "any")]
+    pub http_method: String,
+    /// Matches the path of the requested URL
+    #[serde(default = "any")]
+    pub url_path: String,
+    /// The number of matching requests sampled each second, before the rate
+    /// applies
+    pub fixed_target: u64,
+    /// The rate at which matching requests beyond the fixed target are sampled
+    #[serde(deserialize_with = "rate")]
+    pub rate: f64,
+    #[serde(skip)]
+    reservoir: Reservoir,
+}
+
+impl Rule {
+    /// Creates a rule matching any request
+    pub fn new(fixed_target: u64, rate: f64) -> Self {
+        Rule {
+            description: None,
+            service_name: any(),
+            host: any(),
+            http_method: any(),
+            url_path: any(),
+            fixed_target,
+            rate,
+            reservoir: Reservoir::default(),
+        }
+    }
+
+    /// Whether this rule applies to `request`
+    pub fn matches(&self, request: &SamplingRequest) -> bool {
+        wildcard_match(&self.service_name, request.service_name)
+            && wildcard_match(&self.host, request.host.unwrap_or_default())
+            && wildcard_match(&self.http_method, request.http_method.unwrap_or_default())
+            && wildcard_match(&self.url_path, request.url_path.unwrap_or_default())
+    }
+
+    /// Decides whether a request matching this rule is sampled
+    pub(crate) fn sample(&self) -> bool {
+        self.reservoir.take(self.fixed_target) || rand::random::<f64>() < self.rate
+    }
+}
+
+/// Counts the requests sampled by a rule within the current second
+#[derive(Debug, Default)]
+struct Reservoir {
+    used: Mutex<(u64, u64)>,
+}
+
+impl Reservoir {
+    fn take(&self, per_second: u64) -> bool {
+        let now = Seconds::now().trunc();
+        let mut used = self.used.lock().expect("reservoir lock poisoned");
+        if used.0 != now {
+            *used = (now, 0);
+        }
+        if used.1 < per_second {
+            used.1 += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RuleDocument {
+    #[serde(deserialize_with = "version")]
+    #[allow(dead_code)]
+    version: u8,
+    #[serde(default)]
+    rules: Vec<Rule>,
+    default: Rule,
+}
+
+fn any() -> String {
+    "*".into()
+}
+
+fn version<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match u8::deserialize(deserializer)? {
+        version @ 1..=2 => Ok(version),
+        version => Err(de::Error::custom(format!(
+            "unsupported sampling rule version {}",
+            version
+        ))),
+    }
+}
+
+fn rate<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match f64::deserialize(deserializer)? {
+        rate if (0.0..=1.0).contains(&rate) => Ok(rate),
+        rate => Err(de::Error::custom(format!(
+            "sampling rate {} is not between 0 and 1",
+            rate
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_first_matching_rule() {
+        let sampler = LocalSampler::from_json(
+            r#"{
+                "version": 1,
+                "rules": [
+                    {
+                        "service_name": "checkout",
+                        "http_method": "POST",
+                        "url_path": "/api/orders/*",
+                        "fixed_target": 0,
+                        "rate": 1.0
+                    },
+                    {
+                        "url_path": "/health",
+                        "fixed_target": 0,
+                        "rate": 0.0
+                    }
+                ],
+                "default": { "fixed_target": 0, "rate": 0.0 }
+            }"#,
+        )
+        .expect("valid rules");
+        let order = SamplingRequest {
+            service_name: "checkout",
+            http_method: Some("post"),
+            url_path: Some("/api/orders/42"),
+            ..SamplingRequest::default()
+        };
+        assert!(sampler.sample(&order));
+        assert!(!sampler.sample(&SamplingRequest {
+            http_method: Some("GET"),
+            ..order.clone()
+        }));
+        assert!(!sampler.sample(&SamplingRequest {
+            service_name: "checkout",
+            url_path: Some("/health"),
+            ..SamplingRequest::default()
+        }));
+    }
+
+    #[test]
+    fn reservoir_samples_fixed_target_per_second() {
+        let sampler = LocalSampler::new(Vec::new(), Rule::new(2, 0.0));
+        let request = SamplingRequest::default();
+        let sampled = (0..10).filter(|_| sampler.sample(&request)).count();
+        // the reservoir may refill if the second rolls over mid-loop
+        assert!((2..=4).contains(&sampled));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!(LocalSampler::from_json(
+            r#"{"version": 3, "default": {"fixed_target": 1, "rate": 0.1}}"#
+        )
+        .is_err());
+        assert!(LocalSampler::from_json(
+            r#"{"version": 2, "default": {"fixed_target": 1, "rate": 1.5}}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,92 @@
+//! Decisions about which traces are recorded
+//!
+//! Root segments which do not inherit a sampling decision from an incoming
+//! tracing header consult a [`Sampler`]. Traces which are not sampled are
+//! still tracked, so that their decision can be propagated downstream, but are
+//! never exported.
+
+#[cfg(feature = "centralized-sampling")]
+mod centralized;
+mod local;
+
+#[cfg(feature = "centralized-sampling")]
+pub use centralized::CentralizedSampler;
+pub use local::{LocalSampler, Rule};
+
+/// The properties of a request a sampling decision is made for
+///
+/// The HTTP properties are read from the root span's `http.method`, `http.url`
+/// and `http.host` fields when they are recorded.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SamplingRequest<'a> {
+    /// The name of the service handling the request
+    pub service_name: &'a str,
+    /// The `Host` the request was made to
+    pub host: Option<&'a str>,
+    /// The HTTP method of the request, for example `GET`
+    pub http_method: Option<&'a str>,
+    /// The path of the requested URL
+    pub url_path: Option<&'a str>,
+}
+
+/// Decides whether new traces are recorded
+pub trait Sampler: Send + Sync + 'static {
+    /// Returns true when the trace beginning with `request` should be recorded
+    fn sample(&self, request: &SamplingRequest) -> bool;
+}
+
+impl<F> Sampler for F
+where
+    F: Fn(&SamplingRequest) -> bool + Send + Sync + 'static,
+{
+    fn sample(&self, request: &SamplingRequest) -> bool {
+        self(request)
+    }
+}
+
+/// Matches `value` against `pattern`, ignoring case, where `*` matches any
+/// number of characters and `?` matches exactly one
+pub(crate) fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let value = value.to_lowercase().chars().collect::<Vec<_>>();
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    v = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wildcard_match;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "/api/move"));
+        assert!(wildcard_match("/api/move/*", "/api/move/up"));
+        assert!(wildcard_match("/API/*/up", "/api/move/up"));
+        assert!(wildcard_match("GE?", "get"));
+        assert!(!wildcard_match("GE?", "GETS"));
+        assert!(!wildcard_match("/api/move/*", "/api/score"));
+        assert!(wildcard_match("*.example.com", "www.example.com"));
+    }
+}
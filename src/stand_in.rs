@@ -0,0 +1,97 @@
+//! A local HTTP server standing in for the daemon and metadata services
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::mpsc::{channel, Receiver},
+    thread,
+};
+
+/// A request received by the stand-in
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: String,
+}
+
+impl Request {
+    /// The value of the header `name`, ignoring case
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub(crate) struct StandIn {
+    /// The base URL of the server, such as `http://127.0.0.1:49152`
+    pub(crate) endpoint: String,
+    /// Each request received, once it has been responded to
+    pub(crate) requests: Receiver<Request>,
+}
+
+/// Serves each request with the status and body returned by `handler`
+pub(crate) fn serve<F>(handler: F) -> StandIn
+where
+    F: Fn(&Request) -> (u16, String) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind stand-in");
+    let endpoint = format!("http://{}", listener.local_addr().expect("local address"));
+    let (sender, requests) = channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut reader = BufReader::new(&mut stream);
+            let mut line = String::new();
+            if reader.read_line(&mut line).is_err() {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap_or_default().to_string();
+            let path = parts.next().unwrap_or_default().to_string();
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+                    break;
+                }
+                if let Some((key, value)) = line.split_once(':') {
+                    headers.push((key.trim().to_string(), value.trim().to_string()));
+                }
+            }
+            let mut request = Request {
+                method,
+                path,
+                headers,
+                body: String::new(),
+            };
+            let length = request
+                .header("Content-Length")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            if reader.read_exact(&mut body).is_err() {
+                continue;
+            }
+            request.body = String::from_utf8_lossy(&body).into_owned();
+            let (status, body) = handler(&request);
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {} Stand-In\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            if sender.send(request).is_err() {
+                break;
+            }
+        }
+    });
+    StandIn { endpoint, requests }
+}
@@ -1,14 +1,15 @@
 //! X-Ray [tracing header](https://docs.aws.amazon.com/xray/latest/devguide/xray-concepts.html?shortFooter=true#xray-concepts-tracingheader)
 //! parser
 
-use crate::types::ids::{SegmentId, TraceId};
+use crate::types::ids::{ParseIdError, SegmentId, TraceId};
 use std::{
-    collections::HashMap,
+    collections::HashSet,
+    error::Error,
     fmt::{self, Display},
     str::FromStr,
 };
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
 pub enum SamplingDecision {
     /// Sampled indicates the current segment has been
     /// sampled and will be sent to the X-Ray daemon.
@@ -21,6 +22,7 @@ pub enum SamplingDecision {
     /// back upstream in the response.
     Requested,
     /// Unknown indicates no sampling decision will be made.
+    #[default]
     Unknown,
 }
 
@@ -51,19 +53,19 @@ impl Display for SamplingDecision {
     }
 }
 
-impl Default for SamplingDecision {
-    fn default() -> Self {
-        SamplingDecision::Unknown
-    }
-}
-
 /// Parsed representation of `X-Amzn-Trace-Id` request header
-#[derive(PartialEq, Debug, Default)]
+///
+/// Additional fields are rendered in the order they were parsed or added, so
+/// the same header always renders the same string, but two headers are equal
+/// regardless of the order of their additional fields.
+#[derive(Debug, Default)]
 pub struct Header {
     pub(crate) trace_id: TraceId,
     pub(crate) parent_id: Option<SegmentId>,
     pub(crate) sampling_decision: SamplingDecision,
-    additional_data: HashMap<String, String>,
+    pub(crate) self_id: Option<TraceId>,
+    pub(crate) lineage: Option<Lineage>,
+    additional_data: Vec<(String, String)>,
 }
 
 impl Header {
@@ -89,36 +91,259 @@ impl Header {
         self
     }
 
+    /// Sets the `Self` ID added by an Application Load Balancer
+    pub fn with_self_id(&mut self, self_id: TraceId) -> &mut Self {
+        self.self_id = Some(self_id);
+        self
+    }
+
+    /// Sets the `Lineage` used to detect requests looping between services
+    pub fn with_lineage(&mut self, lineage: Lineage) -> &mut Self {
+        self.lineage = Some(lineage);
+        self
+    }
+
     pub fn with_data<K, V>(&mut self, key: K, value: V) -> &mut Self
     where
         K: Into<String>,
         V: Into<String>,
     {
-        self.additional_data.insert(key.into(), value.into());
+        let (key, value) = (key.into(), value.into());
+        match self.additional_data.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.additional_data.push((key, value)),
+        }
         self
     }
 }
 
-impl FromStr for Header {
-    type Err = String;
+impl PartialEq for Header {
+    fn eq(&self, other: &Self) -> bool {
+        fn sorted(header: &Header) -> Vec<&(String, String)> {
+            let mut data = header.additional_data.iter().collect::<Vec<_>>();
+            data.sort();
+            data
+        }
+        self.trace_id == other.trace_id
+            && self.parent_id == other.parent_id
+            && self.sampling_decision == other.sampling_decision
+            && self.self_id == other.self_id
+            && self.lineage == other.lineage
+            && sorted(self) == sorted(other)
+    }
+}
+
+/// One service's entry in a [`Lineage`]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LineageEntry {
+    /// Hash identifying the service which forwarded the request
+    pub hash: u32,
+    /// How many times the request has passed through that service
+    pub counter: u32,
+}
+
+/// The `Lineage` field of a trace header, which records the services a request
+/// has passed through so that recursive invocations can be detected
+///
+/// It is rendered as `|`-separated entries of an 8 digit hex hash and a
+/// counter, such as `a87bd80c:1|68fd508a:5`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Lineage {
+    entries: Vec<LineageEntry>,
+}
+
+impl Lineage {
+    /// Creates a lineage from its entries
+    pub fn new(entries: Vec<LineageEntry>) -> Self {
+        Lineage { entries }
+    }
+
+    /// The services the request has passed through
+    pub fn entries(&self) -> &[LineageEntry] {
+        &self.entries
+    }
+}
+
+impl FromStr for Lineage {
+    type Err = HeaderError;
+
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        s.split(';')
-            .try_fold(Header::default(), |mut header, line| {
-                if line.starts_with("Root=") {
-                    header.trace_id = TraceId::Rendered(line[5..].into())
-                } else if line.starts_with("Parent=") {
-                    header.parent_id = Some(SegmentId::Rendered(line[7..].into()))
-                } else if line.starts_with("Sampled=") {
-                    header.sampling_decision = line.into();
-                } else if !line.starts_with("Self=") {
-                    let pos = line
-                        .find('=')
-                        .ok_or_else(|| format!("invalid key=value: no `=` found in `{}`", s))?;
-                    let (key, value) = (&line[..pos], &line[pos + 1..]);
-                    header.additional_data.insert(key.into(), value.into());
+        let malformed = || HeaderError::MalformedLineage(s.into());
+        s.split('|')
+            .map(|entry| {
+                let pos = entry.find(':').ok_or_else(malformed)?;
+                let (hash, counter) = (&entry[..pos], &entry[pos + 1..]);
+                if hash.len() != 8 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                    return Err(malformed());
                 }
-                Ok(header)
+                Ok(LineageEntry {
+                    hash: u32::from_str_radix(hash, 16).map_err(|_| malformed())?,
+                    counter: counter.parse().map_err(|_| malformed())?,
+                })
             })
+            .collect::<Result<_, _>>()
+            .map(Lineage::new)
+    }
+}
+
+impl Display for Lineage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (position, entry) in self.entries.iter().enumerate() {
+            if position > 0 {
+                f.write_str("|")?;
+            }
+            write!(f, "{:08x}:{}", entry.hash, entry.counter)?;
+        }
+        Ok(())
+    }
+}
+
+/// How strictly [`Header::parse`] treats malformed fields
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum ParseMode {
+    /// Rejects the header if any field is malformed
+    #[default]
+    Strict,
+    /// Accepts any header with a valid `Root`, skipping malformed fields
+    ///
+    /// A malformed `Parent`, `Self` or `Lineage` is dropped, an unrecognised
+    /// `Sampled` flag leaves the decision [`SamplingDecision::Unknown`], fields
+    /// without an `=` are ignored, and the first of any duplicated keys is
+    /// kept.
+    Lenient,
+}
+
+/// Error returned when a trace header fails to parse
+#[derive(Clone, PartialEq, Debug)]
+pub enum HeaderError {
+    /// The header has no `Root` field
+    MissingRoot,
+    /// The `Root` field is not a valid trace ID
+    MalformedRoot(ParseIdError),
+    /// The `Parent` field is not a valid segment ID
+    MalformedParent(ParseIdError),
+    /// The `Sampled` field is not one of `1`, `0` or `?`
+    InvalidSampled(String),
+    /// The `Self` field is not a valid trace ID
+    MalformedSelf(ParseIdError),
+    /// The `Lineage` field is not a list of `<hash>:<counter>` entries
+    MalformedLineage(String),
+    /// A field is not of the form `key=value`
+    MissingEquals(String),
+    /// A key appears more than once
+    DuplicateKey(String),
+}
+
+impl Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::MissingRoot => f.write_str("trace header has no Root field"),
+            HeaderError::MalformedRoot(error) => write!(f, "malformed Root field: {}", error),
+            HeaderError::MalformedParent(error) => write!(f, "malformed Parent field: {}", error),
+            HeaderError::InvalidSampled(value) => {
+                write!(f, "invalid Sampled flag `{}`, expected 1, 0 or ?", value)
+            }
+            HeaderError::MissingEquals(field) => {
+                write!(f, "invalid key=value: no `=` found in `{}`", field)
+            }
+            HeaderError::MalformedSelf(error) => write!(f, "malformed Self field: {}", error),
+            HeaderError::MalformedLineage(value) => {
+                write!(f, "malformed Lineage field `{}`", value)
+            }
+            HeaderError::DuplicateKey(key) => write!(f, "duplicate key `{}`", key),
+        }
+    }
+}
+
+impl Error for HeaderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HeaderError::MalformedRoot(error)
+            | HeaderError::MalformedParent(error)
+            | HeaderError::MalformedSelf(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl Header {
+    /// Parses a trace header, treating malformed fields according to `mode`
+    ///
+    /// A missing or malformed `Root` is an error in either mode, as there is
+    /// no trace to continue without it.
+    pub fn parse(s: &str, mode: ParseMode) -> Result<Self, HeaderError> {
+        let lenient = mode == ParseMode::Lenient;
+        let mut keys = HashSet::new();
+        let mut trace_id = None;
+        let mut parent_id = None;
+        let mut sampling_decision = SamplingDecision::Unknown;
+        let mut self_id = None;
+        let mut lineage = None;
+        let mut additional_data = Vec::new();
+        for field in s
+            .split(';')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            let (key, value) = match field.find('=') {
+                Some(pos) => (&field[..pos], &field[pos + 1..]),
+                None if lenient => continue,
+                None => return Err(HeaderError::MissingEquals(field.into())),
+            };
+            if !keys.insert(key) {
+                if lenient {
+                    continue;
+                }
+                return Err(HeaderError::DuplicateKey(key.into()));
+            }
+            match key {
+                "Root" => trace_id = Some(value.parse().map_err(HeaderError::MalformedRoot)?),
+                "Parent" => match value.parse() {
+                    Ok(parent) => parent_id = Some(parent),
+                    Err(_) if lenient => (),
+                    Err(error) => return Err(HeaderError::MalformedParent(error)),
+                },
+                "Sampled" => {
+                    sampling_decision = match value {
+                        "1" => SamplingDecision::Sampled,
+                        "0" => SamplingDecision::NotSampled,
+                        "?" => SamplingDecision::Requested,
+                        _ if lenient => SamplingDecision::Unknown,
+                        _ => return Err(HeaderError::InvalidSampled(value.into())),
+                    }
+                }
+                "Self" => match value.parse() {
+                    Ok(id) => self_id = Some(id),
+                    Err(_) if lenient => (),
+                    Err(error) => return Err(HeaderError::MalformedSelf(error)),
+                },
+                "Lineage" => match value.parse() {
+                    Ok(value) => lineage = Some(value),
+                    Err(_) if lenient => (),
+                    Err(error) => return Err(error),
+                },
+                _ => {
+                    additional_data.push((key.into(), value.into()));
+                }
+            }
+        }
+        Ok(Header {
+            trace_id: trace_id.ok_or(HeaderError::MissingRoot)?,
+            parent_id,
+            sampling_decision,
+            self_id,
+            lineage,
+            additional_data,
+        })
+    }
+}
+
+impl FromStr for Header {
+    type Err = HeaderError;
+
+    /// Parses a trace header in [`ParseMode::Strict`] mode
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Header::parse(s, ParseMode::Strict)
     }
 }
 
@@ -131,6 +356,12 @@ impl Display for Header {
         if self.sampling_decision != SamplingDecision::Unknown {
             write!(f, ";{}", self.sampling_decision)?;
         }
+        if let Some(self_id) = &self.self_id {
+            write!(f, ";Self={}", self_id)?;
+        }
+        if let Some(lineage) = &self.lineage {
+            write!(f, ";Lineage={}", lineage)?;
+        }
         for (k, v) in &self.additional_data {
             write!(f, ";{}={}", k, v)?;
         }
@@ -147,8 +378,8 @@ mod tests {
             "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1"
                 .parse::<Header>(),
             Ok(Header {
-                trace_id: TraceId::Rendered("1-5759e988-bd862e3fe1be46a994272793".into()),
-                parent_id: Some(SegmentId::Rendered("53995c3f42cd8ad8".into())),
+                trace_id: "1-5759e988-bd862e3fe1be46a994272793".parse().unwrap(),
+                parent_id: Some("53995c3f42cd8ad8".parse().unwrap()),
                 sampling_decision: SamplingDecision::Sampled,
                 ..Header::default()
             })
@@ -159,7 +390,7 @@ mod tests {
         assert_eq!(
             "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1".parse::<Header>(),
             Ok(Header {
-                trace_id: TraceId::Rendered("1-5759e988-bd862e3fe1be46a994272793".into()),
+                trace_id: "1-5759e988-bd862e3fe1be46a994272793".parse().unwrap(),
                 parent_id: None,
                 sampling_decision: SamplingDecision::Sampled,
                 ..Header::default()
@@ -170,7 +401,7 @@ mod tests {
     #[test]
     fn displays_as_header() {
         let header = Header {
-            trace_id: TraceId::Rendered("1-5759e988-bd862e3fe1be46a994272793".into()),
+            trace_id: "1-5759e988-bd862e3fe1be46a994272793".parse().unwrap(),
             ..Header::default()
         };
         assert_eq!(
@@ -178,4 +409,100 @@ mod tests {
             "Root=1-5759e988-bd862e3fe1be46a994272793"
         );
     }
+
+    #[test]
+    fn strict_parse_rejects_malformed_fields() {
+        let root = "Root=1-5759e988-bd862e3fe1be46a994272793";
+        assert_eq!(
+            "Parent=53995c3f42cd8ad8".parse::<Header>(),
+            Err(HeaderError::MissingRoot)
+        );
+        assert_eq!(
+            "Root=hello".parse::<Header>(),
+            Err(HeaderError::MalformedRoot(ParseIdError::TraceIdFormat))
+        );
+        assert_eq!(
+            format!("{};Parent=53995c3f", root).parse::<Header>(),
+            Err(HeaderError::MalformedParent(ParseIdError::SegmentIdLength))
+        );
+        assert_eq!(
+            format!("{};Sampled=yes", root).parse::<Header>(),
+            Err(HeaderError::InvalidSampled("yes".into()))
+        );
+        assert_eq!(
+            format!("{};Lineage", root).parse::<Header>(),
+            Err(HeaderError::MissingEquals("Lineage".into()))
+        );
+        assert_eq!(
+            format!("{};Sampled=1;Sampled=0", root).parse::<Header>(),
+            Err(HeaderError::DuplicateKey("Sampled".into()))
+        );
+    }
+
+    #[test]
+    fn lenient_parse_skips_malformed_fields() {
+        let header = Header::parse(
+            "Root=1-5759e988-bd862e3fe1be46a994272793; Parent=zz;Sampled=yes;Foo;Bar=1;Bar=2;",
+            ParseMode::Lenient,
+        )
+        .unwrap();
+        assert_eq!(header.parent_id, None);
+        assert_eq!(header.sampling_decision, SamplingDecision::Unknown);
+        assert_eq!(
+            header.to_string(),
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Bar=1"
+        );
+        assert_eq!(
+            Header::parse("Root=hello", ParseMode::Lenient),
+            Err(HeaderError::MalformedRoot(ParseIdError::TraceIdFormat))
+        );
+    }
+
+    #[test]
+    fn round_trips_self_and_lineage() {
+        let value = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1;\
+                     Self=1-67891234-12456789abcdef0123456789;Lineage=a87bd80c:1|68fd508a:5";
+        let header = value.parse::<Header>().unwrap();
+        assert_eq!(
+            header.self_id,
+            Some("1-67891234-12456789abcdef0123456789".parse().unwrap())
+        );
+        assert_eq!(
+            header.lineage.as_ref().map(Lineage::entries),
+            Some(
+                &[
+                    LineageEntry {
+                        hash: 0xa87b_d80c,
+                        counter: 1
+                    },
+                    LineageEntry {
+                        hash: 0x68fd_508a,
+                        counter: 5
+                    },
+                ][..]
+            )
+        );
+        assert_eq!(header.to_string(), value);
+        assert_eq!(
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Lineage=a87bd80c".parse::<Header>(),
+            Err(HeaderError::MalformedLineage("a87bd80c".into()))
+        );
+    }
+
+    #[test]
+    fn renders_additional_data_in_order() {
+        let value = "Root=1-5759e988-bd862e3fe1be46a994272793;Zeta=1;Alpha=2;Mu=3";
+        let header = value.parse::<Header>().unwrap();
+        assert_eq!(header.to_string(), value);
+        let mut reordered = "Root=1-5759e988-bd862e3fe1be46a994272793;Mu=3;Zeta=1;Alpha=2"
+            .parse::<Header>()
+            .unwrap();
+        assert_eq!(header, reordered);
+        reordered.with_data("Zeta", "4").with_data("Beta", "5");
+        assert_eq!(
+            reordered.to_string(),
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Mu=3;Zeta=4;Alpha=2;Beta=5"
+        );
+        assert_ne!(header, reordered);
+    }
 }
@@ -0,0 +1,148 @@
+//! Conversions between trace data and the [`http`](::http) crate's types
+
+use crate::types::{
+    header::{Header, ParseMode},
+    types::{Http, Request, Response},
+};
+use ::http::{header, HeaderMap, HeaderName, HeaderValue};
+
+impl Header {
+    /// Reads the `X-Amzn-Trace-Id` header from `headers`
+    ///
+    /// The header is parsed in [`ParseMode::Lenient`] mode, and is `None` if
+    /// it is missing or has no valid `Root`.
+    pub fn from_header_map(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(Header::NAME)?.to_str().ok()?;
+        Header::parse(value, ParseMode::Lenient).ok()
+    }
+
+    /// Sets the `X-Amzn-Trace-Id` header in `headers` to this header,
+    /// replacing any existing value
+    ///
+    /// Headers with additional data which is not a valid header value are
+    /// left out.
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        if let Ok(value) = HeaderValue::from_str(&self.to_string()) {
+            headers.insert(HeaderName::from_static("x-amzn-trace-id"), value);
+        }
+    }
+}
+
+impl<B> From<&::http::Request<B>> for Request {
+    /// Describes an incoming request
+    ///
+    /// The client IP is read from the `X-Forwarded-For` header when one is
+    /// present. Otherwise it is left for the server, which knows the remote
+    /// address of the connection, to fill in.
+    fn from(request: &::http::Request<B>) -> Self {
+        let headers = request.headers();
+        let client_ip = header_str(headers, "x-forwarded-for")
+            .and_then(|forwarded| forwarded.split(',').next())
+            .map(|ip| ip.trim().to_string());
+        Request {
+            method: Some(request.method().to_string()),
+            url: Some(url(request)),
+            x_forwarded_for: client_ip.as_ref().map(|_| true),
+            client_ip,
+            user_agent: header_str(headers, header::USER_AGENT.as_str()).map(String::from),
+            traced: None,
+        }
+    }
+}
+
+impl<B> From<&::http::Response<B>> for Response {
+    fn from(response: &::http::Response<B>) -> Self {
+        Response {
+            status: Some(response.status().as_u16()),
+            content_length: header_str(response.headers(), header::CONTENT_LENGTH.as_str())
+                .and_then(|length| length.parse().ok()),
+        }
+    }
+}
+
+impl Http {
+    /// Describes a request and the response it received
+    pub fn new<B, C>(request: &::http::Request<B>, response: &::http::Response<C>) -> Self {
+        Http {
+            request: Some(request.into()),
+            response: Some(response.into()),
+        }
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name)?.to_str().ok()
+}
+
+/// The full URL of a request, which servers usually receive as only a path
+/// along with a `Host` header
+fn url<B>(request: &::http::Request<B>) -> String {
+    let uri = request.uri();
+    if uri.scheme().is_some() {
+        return uri.to_string();
+    }
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    match header_str(request.headers(), header::HOST.as_str()) {
+        Some(host) => {
+            let scheme = header_str(request.headers(), "x-forwarded-proto").unwrap_or("http");
+            format!("{}://{}{}", scheme, host, path)
+        }
+        None => path.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::header::SamplingDecision;
+
+    #[test]
+    fn extracts_and_injects_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-AMZN-TRACE-ID",
+            HeaderValue::from_static("Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1"),
+        );
+        let mut header = Header::from_header_map(&headers).unwrap();
+        assert_eq!(header.sampling_decision, SamplingDecision::Sampled);
+        header.with_parent_id("53995c3f42cd8ad8".parse().unwrap());
+        header.inject(&mut headers);
+        assert_eq!(
+            headers.get_all(Header::NAME).iter().collect::<Vec<_>>(),
+            ["Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1"]
+        );
+        assert!(Header::from_header_map(&HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn describes_requests_and_responses() {
+        let request = ::http::Request::post("/orders?id=1")
+            .header("host", "example.com")
+            .header("user-agent", "curl/8.0")
+            .header("x-forwarded-for", "203.0.113.1, 10.0.0.1")
+            .body(())
+            .unwrap();
+        let response = ::http::Response::builder()
+            .status(201)
+            .header("content-length", "42")
+            .body(())
+            .unwrap();
+        let http = Http::new(&request, &response);
+        assert_eq!(
+            serde_json::to_value(&http).unwrap(),
+            serde_json::json!({
+                "request": {
+                    "method": "POST",
+                    "url": "http://example.com/orders?id=1",
+                    "client_ip": "203.0.113.1",
+                    "user_agent": "curl/8.0",
+                    "x_forwarded_for": true,
+                },
+                "response": {
+                    "status": 201,
+                    "content_length": 42,
+                },
+            })
+        );
+    }
+}
@@ -1,15 +1,51 @@
 use super::{time::Seconds, types::Bytes};
 use rand::RngCore;
 use serde::{de, ser, Serializer};
-use std::fmt;
+use std::{error::Error, fmt, str::FromStr};
+
+/// Error returned when a trace or segment ID fails to parse
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ParseIdError {
+    /// A trace ID did not have the `1-<8 hex digits>-<24 hex digits>` layout
+    TraceIdFormat,
+    /// A segment ID was not 16 digits long
+    SegmentIdLength,
+    /// The ID contained a character which is not a hexadecimal digit
+    InvalidDigit,
+}
+
+impl fmt::Display for ParseIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseIdError::TraceIdFormat => {
+                f.write_str("trace ID must be of the form `1-<8 hex digits>-<24 hex digits>`")
+            }
+            ParseIdError::SegmentIdLength => f.write_str("segment ID must be 16 hex digits"),
+            ParseIdError::InvalidDigit => f.write_str("ID contains a non-hexadecimal digit"),
+        }
+    }
+}
+
+impl Error for ParseIdError {}
+
+/// Decodes exactly `N` bytes from `2 * N` hexadecimal digits
+fn decode_hex<const N: usize>(hex: &str) -> Result<[u8; N], ParseIdError> {
+    if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(ParseIdError::InvalidDigit);
+    }
+    let mut bytes = [0; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| ParseIdError::InvalidDigit)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| ParseIdError::InvalidDigit)?;
+    }
+    Ok(bytes)
+}
 
 /// Unique identifier of an operation within a trace
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum SegmentId {
     #[doc(hidden)]
     New([u8; 8]),
-    #[doc(hidden)]
-    Rendered(String),
 }
 
 impl SegmentId {
@@ -25,11 +61,21 @@ impl fmt::Display for SegmentId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SegmentId::New(bytes) => write!(f, "{:x}", Bytes(bytes)),
-            SegmentId::Rendered(value) => write!(f, "{}", value),
         }
     }
 }
 
+impl FromStr for SegmentId {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 16 {
+            return Err(ParseIdError::SegmentIdLength);
+        }
+        decode_hex(s).map(SegmentId::New)
+    }
+}
+
 impl Default for SegmentId {
     fn default() -> Self {
         SegmentId::new()
@@ -42,14 +88,14 @@ impl<'de> de::Visitor<'de> for SegmentIdVisitor {
     type Value = SegmentId;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a string value")
+        formatter.write_str("a segment ID of 16 hex digits")
     }
 
     fn visit_str<E>(self, value: &str) -> Result<SegmentId, E>
     where
         E: de::Error,
     {
-        Ok(SegmentId::Rendered(value.into()))
+        value.parse().map_err(E::custom)
     }
 }
 
@@ -71,12 +117,11 @@ impl<'de> de::Deserialize<'de> for SegmentId {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// Unique identifier of a trace, shared by all of its segments
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum TraceId {
     #[doc(hidden)]
     New(u64, [u8; 12]),
-    #[doc(hidden)]
-    Rendered(String),
 }
 
 impl TraceId {
@@ -98,7 +143,26 @@ impl fmt::Display for TraceId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             TraceId::New(seconds, bytes) => write!(f, "1-{:08x}-{:x}", seconds, Bytes(bytes)),
-            TraceId::Rendered(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl FromStr for TraceId {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('-');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("1"), Some(seconds), Some(unique), None)
+                if seconds.len() == 8 && unique.len() == 24 =>
+            {
+                let seconds = decode_hex::<4>(seconds)?;
+                Ok(TraceId::New(
+                    u32::from_be_bytes(seconds).into(),
+                    decode_hex(unique)?,
+                ))
+            }
+            _ => Err(ParseIdError::TraceIdFormat),
         }
     }
 }
@@ -109,13 +173,14 @@ impl<'de> de::Visitor<'de> for TraceIdVisitor {
     type Value = TraceId;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a string value")
+        formatter.write_str("a trace ID of the form `1-<8 hex digits>-<24 hex digits>`")
     }
+
     fn visit_str<E>(self, value: &str) -> Result<TraceId, E>
     where
         E: de::Error,
     {
-        Ok(TraceId::Rendered(value.into()))
+        value.parse().map_err(E::custom)
     }
 }
 
@@ -136,3 +201,56 @@ impl<'de> de::Deserialize<'de> for TraceId {
         deserializer.deserialize_str(TraceIdVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_displays_ids() {
+        let trace_id = "1-5759e988-bd862e3fe1be46a994272793";
+        assert_eq!(trace_id.parse::<TraceId>().unwrap().to_string(), trace_id);
+        assert_eq!(
+            "1-5759E988-BD862E3FE1BE46A994272793".parse::<TraceId>(),
+            trace_id.parse()
+        );
+        let segment_id = "53995c3f42cd8ad8";
+        assert_eq!(
+            segment_id.parse::<SegmentId>(),
+            Ok(SegmentId::New([
+                0x53, 0x99, 0x5c, 0x3f, 0x42, 0xcd, 0x8a, 0xd8
+            ]))
+        );
+        let id = SegmentId::new();
+        assert_eq!(id.to_string().parse(), Ok(id));
+    }
+
+    #[test]
+    fn rejects_malformed_ids() {
+        for trace_id in &[
+            "hello",
+            "2-5759e988-bd862e3fe1be46a994272793",
+            "1-5759e98-bd862e3fe1be46a994272793",
+            "1-5759e988-bd862e3fe1be46a99427279",
+            "1-5759e988-bd862e3fe1be46a994272793-00",
+        ] {
+            assert_eq!(
+                trace_id.parse::<TraceId>(),
+                Err(ParseIdError::TraceIdFormat)
+            );
+        }
+        assert_eq!(
+            "1-5759e988-bd862e3fe1be46a99427279z".parse::<TraceId>(),
+            Err(ParseIdError::InvalidDigit)
+        );
+        assert_eq!(
+            "53995c3f".parse::<SegmentId>(),
+            Err(ParseIdError::SegmentIdLength)
+        );
+        assert_eq!(
+            "+3995c3f42cd8ad8".parse::<SegmentId>(),
+            Err(ParseIdError::InvalidDigit)
+        );
+        assert!(serde_json::from_str::<SegmentId>("\"hello\"").is_err());
+    }
+}
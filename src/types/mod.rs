@@ -1,7 +1,7 @@
 pub mod header;
+#[cfg(feature = "http")]
+mod http;
 pub mod ids;
 pub mod time;
+#[allow(clippy::module_inception)]
 pub mod types;
-
-pub use ids as _;
-pub use types as _;
@@ -40,9 +40,9 @@ impl From<Duration> for Seconds {
     }
 }
 
-impl Into<Duration> for Seconds {
-    fn into(self) -> Duration {
-        let Seconds(secs) = self;
+impl From<Seconds> for Duration {
+    fn from(seconds: Seconds) -> Self {
+        let Seconds(secs) = seconds;
         Duration::new(secs.trunc() as u64, (secs.fract() * 1.0e9) as u32)
     }
 }
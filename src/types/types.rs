@@ -1,9 +1,10 @@
 use super::{
+    header::{Header, SamplingDecision},
     ids::{SegmentId, TraceId},
     time::Seconds,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::fmt;
 use std::ops::Not;
@@ -75,13 +76,14 @@ pub struct Segment {
     /// A string that identifies the user who sent the request.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
-    ///
+    /// The ARN of the AWS resource running the application.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resource_arn: Option<String>,
     /// http objects with information about the original HTTP request.
-    /// #[serde(skip_serializing_if = "Option::is_none")] pub http:
-    /// Option<Http>, annotations object with key-value pairs that you want
-    /// X-Ray to index for search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http: Option<Http>,
+    /// annotations object with key-value pairs that you want X-Ray to index
+    /// for search.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub annotations: Option<HashMap<String, Annotation>>,
     /// metadata object with any additional data that you want to store in the
@@ -95,6 +97,14 @@ pub struct Segment {
     /// An object with information about your application.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub service: Option<Service>,
+    /// Subsegments recording work done within this segment, embedded in the
+    /// segment document.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subsegments: Vec<Subsegment>,
+    /// Whether the trace this segment belongs to is sampled. Not part of the
+    /// segment document.
+    #[serde(skip)]
+    pub(crate) sampling_decision: SamplingDecision,
 }
 
 impl Segment {
@@ -105,16 +115,57 @@ impl Segment {
     where
         N: Into<String>,
     {
-        let mut valid_name = name.into();
-        if valid_name.len() > 200 {
-            valid_name = valid_name[..200].into();
+        Segment {
+            name: truncate_name(name.into()),
+            ..Segment::default()
         }
+    }
+
+    /// Continues the trace described by an incoming tracing header
+    ///
+    /// The segment joins the header's trace, records the upstream caller as its
+    /// parent and adopts the caller's sampling decision.
+    pub fn with_header(&mut self, header: Header) -> &mut Self {
+        self.trace_id = header.trace_id;
+        self.parent_id = header.parent_id;
+        self.sampling_decision = header.sampling_decision;
+        self
+    }
+
+    /// An in-progress copy of this segment, without its subsegments or
+    /// metadata, to be sent while the work it describes is still running
+    pub fn to_in_progress(&self) -> Segment {
         Segment {
-            name: valid_name,
+            trace_id: self.trace_id,
+            id: self.id,
+            name: self.name.clone(),
+            start_time: Seconds(self.start_time.0),
+            in_progress: true,
+            parent_id: self.parent_id,
+            origin: self.origin.clone(),
+            user: self.user.clone(),
+            resource_arn: self.resource_arn.clone(),
+            http: self.http.clone(),
+            aws: self.aws.clone(),
+            service: self.service.clone(),
+            annotations: self.annotations.clone(),
             ..Segment::default()
         }
     }
 
+    /// Begins a new named subsegment of this segment
+    ///
+    /// The subsegment is independent, recording this segment's trace and id,
+    /// so that it may be sent on its own or embedded once complete.
+    pub fn subsegment<N>(&self, name: N) -> Subsegment
+    where
+        N: Into<String>,
+    {
+        let mut subsegment = Subsegment::begin(name);
+        subsegment.independent(self.trace_id, self.id);
+        subsegment
+    }
+
     /// End the segment by assigning its end_time
     pub fn end(&mut self) -> &mut Self {
         self.end_time = Some(Seconds::now());
@@ -123,15 +174,513 @@ impl Segment {
     }
 }
 
+/// Truncates a segment or subsegment name to the 200 characters X-Ray accepts
+fn truncate_name(name: String) -> String {
+    match name.char_indices().nth(200) {
+        Some((end, _)) => name[..end].into(),
+        None => name,
+    }
+}
+
+/// Checks a segment or subsegment name against the characters X-Ray accepts
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("name must not be empty".into());
+    }
+    if name.chars().count() > 200 {
+        return Err(format!("name `{}` is longer than 200 characters", name));
+    }
+    match name
+        .chars()
+        .find(|c| !(c.is_alphanumeric() || c.is_whitespace() || "_.:/%&#=+\\-@".contains(*c)))
+    {
+        Some(c) => Err(format!(
+            "name `{}` contains invalid character `{}`",
+            name, c
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Records work done within a segment, such as a downstream call or a
+/// database query
+///
+/// Subsegments are usually embedded in their parent's document, but may also
+/// be sent to X-Ray as independent documents, in which case they carry the
+/// trace and parent they belong to.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Subsegment {
+    ///  A 64-bit identifier for the subsegment, unique among segments in the
+    ///  same trace, in 16 hexadecimal digits.
+    pub(crate) id: SegmentId,
+    /// The logical name of the subsegment, up to 200 characters. For
+    /// downstream calls, name the subsegment after the resource or service
+    /// called.
+    pub(crate) name: String,
+    /// Number that is the time the subsegment was created, in floating point
+    /// seconds in epoch time.
+    pub(crate) start_time: Seconds,
+    /// Number that is the time the subsegment was closed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<Seconds>,
+    /// boolean that is set to true instead of specifying an end_time to record
+    /// that a subsegment is started, but is not complete.
+    #[serde(default, skip_serializing_if = "Not::not")]
+    pub in_progress: bool,
+    /// Fields only present when the subsegment is sent on its own.
+    #[serde(flatten)]
+    pub(crate) independent: Option<Independent>,
+    /// aws for AWS SDK calls; remote for other downstream calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<Namespace>,
+    /// array of subsegment IDs that identifies subsegments with the same
+    /// parent that completed prior to this subsegment.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub precursor_ids: Vec<SegmentId>,
+    /// Indicates that a server error occurred (response status code was 5XX
+    /// Server Error).
+    #[serde(default, skip_serializing_if = "Not::not")]
+    pub fault: bool,
+    /// Indicates that a client error occurred (response status code was 4XX
+    /// Client Error).
+    #[serde(default, skip_serializing_if = "Not::not")]
+    pub error: bool,
+    /// boolean indicating that a request was throttled (response status code
+    /// was 429 Too Many Requests).
+    #[serde(default, skip_serializing_if = "Not::not")]
+    pub throttle: bool,
+    ///  error fields that indicate an error occurred and that include
+    ///  information about the exception that caused the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cause: Option<Cause>,
+    /// http object with information about an outgoing HTTP call.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http: Option<Http>,
+    /// aws object with information about the downstream AWS resource that
+    /// your application called.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aws: Option<AwsOperation>,
+    /// sql object with information about queries made to an SQL database.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sql: Option<Sql>,
+    /// annotations object with key-value pairs that you want X-Ray to index
+    /// for search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, Annotation>>,
+    /// metadata object with any additional data that you want to store in the
+    /// subsegment.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, Value>>,
+    /// array of subsegment objects.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subsegments: Vec<Subsegment>,
+    /// Whether the trace an independent subsegment without a parent span
+    /// belongs to is sampled, as in AWS Lambda. Not part of the subsegment
+    /// document.
+    #[serde(skip)]
+    pub(crate) sampling_decision: SamplingDecision,
+}
+
+/// Fields which identify the trace and parent of an independent subsegment
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Independent {
+    #[serde(rename = "type")]
+    kind: SubsegmentType,
+    pub(crate) trace_id: TraceId,
+    pub(crate) parent_id: SegmentId,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum SubsegmentType {
+    Subsegment,
+}
+
+impl Subsegment {
+    /// Begins a new named subsegment, to be embedded in its parent's document
+    pub fn begin<N>(name: N) -> Self
+    where
+        N: Into<String>,
+    {
+        Subsegment {
+            name: truncate_name(name.into()),
+            ..Subsegment::default()
+        }
+    }
+
+    /// Begins a new named subsegment of this subsegment
+    ///
+    /// The child is independent when this subsegment is, and embedded
+    /// otherwise.
+    pub fn subsegment<N>(&self, name: N) -> Subsegment
+    where
+        N: Into<String>,
+    {
+        let mut subsegment = Subsegment::begin(name);
+        if let Some(independent) = &self.independent {
+            subsegment.independent(independent.trace_id, self.id);
+        }
+        subsegment
+    }
+
+    /// Marks this subsegment to be sent on its own, as part of `trace_id` and
+    /// within the segment or subsegment `parent_id`
+    pub fn independent(&mut self, trace_id: TraceId, parent_id: SegmentId) -> &mut Self {
+        self.independent = Some(Independent {
+            kind: SubsegmentType::Subsegment,
+            trace_id,
+            parent_id,
+        });
+        self
+    }
+
+    /// Marks this subsegment to be embedded in its parent's document
+    pub fn embedded(&mut self) -> &mut Self {
+        self.independent = None;
+        self
+    }
+
+    /// Whether this subsegment is sent on its own rather than embedded
+    pub fn is_independent(&self) -> bool {
+        self.independent.is_some()
+    }
+
+    /// End the subsegment by assigning its end_time
+    pub fn end(&mut self) -> &mut Self {
+        self.end_time = Some(Seconds::now());
+        self.in_progress = false;
+        self
+    }
+
+    /// Checks that this subsegment, and those embedded in it, form a valid
+    /// X-Ray document
+    pub fn validate(&self) -> Result<(), String> {
+        validate_name(&self.name)?;
+        match (&self.end_time, self.in_progress) {
+            (Some(_), true) => {
+                return Err(format!(
+                    "subsegment `{}` has both an end_time and in_progress",
+                    self.name
+                ))
+            }
+            (None, false) => {
+                return Err(format!(
+                    "subsegment `{}` has neither an end_time nor in_progress",
+                    self.name
+                ))
+            }
+            (Some(Seconds(end)), false) if *end < self.start_time.0 => {
+                return Err(format!("subsegment `{}` ends before it starts", self.name))
+            }
+            _ => (),
+        }
+        if self.precursor_ids.contains(&self.id) {
+            return Err(format!(
+                "subsegment `{}` lists itself as a precursor",
+                self.name
+            ));
+        }
+        self.subsegments.iter().try_for_each(|subsegment| {
+            if subsegment.is_independent() {
+                return Err(format!(
+                    "embedded subsegment `{}` must not carry a trace_id, parent_id or type",
+                    subsegment.name
+                ));
+            }
+            subsegment.validate()
+        })
+    }
+}
+
+/// The kind of downstream call a subsegment records
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Namespace {
+    /// A call made through an AWS SDK
+    Aws,
+    /// A call to any other downstream service
+    Remote,
+}
+
+/// Information about a downstream call to an AWS service
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AwsOperation {
+    /// The name of the API action invoked against an AWS service or resource.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation: Option<String>,
+    /// If your application accesses resources in a different account, or sends
+    /// segments to a different account, record the ID of the account that owns
+    /// the AWS resource that your application accessed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
+    /// If the resource is in a region different from your application, record
+    /// the region.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// Unique identifier for the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// For operations on an Amazon SQS queue, the queue's URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_url: Option<String>,
+    /// For operations on a DynamoDB table, the name of the table.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub table_name: Option<String>,
+}
+
+/// Information about a query made to an SQL database
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Sql {
+    /// For SQL Server or other database connections that don't use URL
+    /// connection strings, record the connection string, excluding passwords.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_string: Option<String>,
+    /// For a database connection that uses a URL connection string, record the
+    /// URL, excluding passwords.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The database query, with any user provided values removed or replaced
+    /// by a placeholder.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sanitized_query: Option<String>,
+    /// The name of the database engine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database_type: Option<String>,
+    /// The version number of the database engine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database_version: Option<String>,
+    /// The name and version number of the database engine driver that your
+    /// application uses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub driver_version: Option<String>,
+    /// The database username.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// call if the query used a PreparedCall; statement if the query used a
+    /// PreparedStatement.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preparation: Option<Preparation>,
+}
+
+/// How an SQL query was prepared
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preparation {
+    /// The query used a PreparedCall
+    Call,
+    /// The query used a PreparedStatement
+    Statement,
+}
+
+/// A document which may be sent to X-Ray on its own
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Document {
+    /// A segment, along with any subsegments embedded in it
+    Segment(Segment),
+    /// An independent subsegment
+    Subsegment(Subsegment),
+}
+
+impl Document {
+    /// Begins a new named subsegment of this document
+    pub fn subsegment<N>(&self, name: N) -> Subsegment
+    where
+        N: Into<String>,
+    {
+        match self {
+            Document::Segment(segment) => segment.subsegment(name),
+            Document::Subsegment(subsegment) => subsegment.subsegment(name),
+        }
+    }
+
+    /// Embeds a completed subsegment in this document
+    pub fn embed(&mut self, mut subsegment: Subsegment) -> &mut Self {
+        subsegment.embedded();
+        match self {
+            Document::Segment(segment) => segment.subsegments.push(subsegment),
+            Document::Subsegment(parent) => parent.subsegments.push(subsegment),
+        }
+        self
+    }
+
+    /// Adds an annotation which X-Ray indexes for use with filter expressions
+    pub fn annotate<K>(&mut self, key: K, value: Annotation) -> &mut Self
+    where
+        K: Into<String>,
+    {
+        let annotations = match self {
+            Document::Segment(segment) => &mut segment.annotations,
+            Document::Subsegment(subsegment) => &mut subsegment.annotations,
+        };
+        annotations
+            .get_or_insert_with(HashMap::new)
+            .insert(key.into(), value);
+        self
+    }
+
+    /// Adds unindexed metadata under `namespace`
+    pub fn add_metadata<K>(&mut self, namespace: &str, key: K, value: Value) -> &mut Self
+    where
+        K: Into<String>,
+    {
+        if let Some(values) = self.metadata_namespace(namespace) {
+            values.insert(key.into(), value);
+        }
+        self
+    }
+
+    /// Appends a value to the unindexed metadata array `key` under `namespace`
+    pub fn append_metadata<K>(&mut self, namespace: &str, key: K, value: Value) -> &mut Self
+    where
+        K: Into<String>,
+    {
+        if let Some(values) = self.metadata_namespace(namespace) {
+            match values
+                .entry(key.into())
+                .or_insert_with(|| Value::Array(Vec::new()))
+            {
+                Value::Array(array) => array.push(value),
+                other => *other = Value::Array(vec![other.take(), value]),
+            }
+        }
+        self
+    }
+
+    fn metadata_namespace(&mut self, namespace: &str) -> Option<&mut Map<String, Value>> {
+        let metadata = match self {
+            Document::Segment(segment) => &mut segment.metadata,
+            Document::Subsegment(subsegment) => &mut subsegment.metadata,
+        };
+        match metadata
+            .get_or_insert_with(HashMap::new)
+            .entry(namespace.into())
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            Value::Object(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Records exceptions which occurred during the work this document
+    /// describes, marking it as faulted
+    pub fn add_exceptions<I>(&mut self, exceptions: I) -> &mut Self
+    where
+        I: IntoIterator<Item = Exception>,
+    {
+        let (fault, cause) = match self {
+            Document::Segment(segment) => (&mut segment.fault, &mut segment.cause),
+            Document::Subsegment(subsegment) => (&mut subsegment.fault, &mut subsegment.cause),
+        };
+        *fault = true;
+        if let None | Some(Cause::Name(_)) = cause {
+            *cause = Some(Cause::Description {
+                working_directory: std::env::current_dir()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_default(),
+                paths: Vec::new(),
+                exceptions: Vec::new(),
+            });
+        }
+        if let Some(Cause::Description {
+            exceptions: recorded,
+            ..
+        }) = cause
+        {
+            recorded.extend(exceptions);
+        }
+        self
+    }
+
+    /// Replaces the name of this document, truncating it to 200 characters
+    pub fn rename<N>(&mut self, name: N) -> &mut Self
+    where
+        N: Into<String>,
+    {
+        let name = truncate_name(name.into());
+        match self {
+            Document::Segment(segment) => segment.name = name,
+            Document::Subsegment(subsegment) => subsegment.name = name,
+        }
+        self
+    }
+
+    /// Information about the HTTP request this document describes, which is
+    /// added if there is none
+    pub fn http_mut(&mut self) -> &mut Http {
+        let http = match self {
+            Document::Segment(segment) => &mut segment.http,
+            Document::Subsegment(subsegment) => &mut subsegment.http,
+        };
+        http.get_or_insert_with(Http::default)
+    }
+
+    /// Records the status of the HTTP response, marking the document as
+    /// throttled (429), errored (other 4XX) or faulted (5XX)
+    pub fn record_status(&mut self, status: u16) -> &mut Self {
+        self.http_mut()
+            .response
+            .get_or_insert_with(Response::default)
+            .status = Some(status);
+        let (error, fault, throttle) = match self {
+            Document::Segment(segment) => (
+                &mut segment.error,
+                &mut segment.fault,
+                &mut segment.throttle,
+            ),
+            Document::Subsegment(subsegment) => (
+                &mut subsegment.error,
+                &mut subsegment.fault,
+                &mut subsegment.throttle,
+            ),
+        };
+        match status {
+            429 => {
+                *error = true;
+                *throttle = true;
+            }
+            400..=499 => *error = true,
+            500..=599 => *fault = true,
+            _ => (),
+        }
+        self
+    }
+
+    /// End the document by assigning its end_time
+    pub fn end(&mut self) -> &mut Self {
+        match self {
+            Document::Segment(segment) => {
+                segment.end();
+            }
+            Document::Subsegment(subsegment) => {
+                subsegment.end();
+            }
+        }
+        self
+    }
+}
+
+impl From<Segment> for Document {
+    fn from(segment: Segment) -> Self {
+        Document::Segment(segment)
+    }
+}
+
+impl From<Subsegment> for Document {
+    fn from(subsegment: Subsegment) -> Self {
+        Document::Subsegment(subsegment)
+    }
+}
+
 /// A value type which may be used for
 /// filter querying
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Annotation {
     /// A string value
     String(String),
     /// A numberic value
-    Number(usize),
+    Number(serde_json::Number),
     /// A boolean value
     Bool(bool),
 }
@@ -143,7 +692,7 @@ impl Default for Annotation {
 }
 
 /// Describes an http request/response cycle
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Http {
     /// Information about a request
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -154,7 +703,7 @@ pub struct Http {
 }
 
 ///  Information about a request.
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Request {
     /// The request method. For example, GET.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -170,14 +719,14 @@ pub struct Request {
     pub user_agent: Option<String>,
     /// (segments only) boolean indicating that the client_ip was read from an X-Forwarded-For header and is not reliable as it could have been forged.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub x_forwarded_for: Option<String>,
+    pub x_forwarded_for: Option<bool>,
     /// (subsegments only) boolean indicating that the downstream call is to another traced service. If this field is set to true, X-Ray considers the trace to be broken until the downstream service uploads a segment with a parent_id that matches the id of the subsegment that contains this block.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub traced: Option<bool>,
 }
 
 ///  Information about a response.
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Response {
     /// number indicating the HTTP status of the response.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -188,7 +737,7 @@ pub struct Response {
 }
 
 ///  An object with information about your application.
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Service {
     /// A string that identifies the version of your application that served the request.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -196,7 +745,7 @@ pub struct Service {
 }
 
 /// Context information about the AWS environment this segment was run in
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct Aws {
     ///  If your application sends segments to a different AWS account, record the ID of the account running your application.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -210,25 +759,57 @@ pub struct Aws {
     /// Information about an Elastic Beanstalk environment. You can find this information in a file named /var/elasticbeanstalk/xray/environment.conf on the latest Elastic Beanstalk platforms.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub elastic_beanstalk: Option<ElasticBeanstalk>,
+    /// The CloudWatch Logs log groups your application writes to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cloudwatch_logs: Vec<CloudwatchLogs>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tracing: Option<Tracing>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub xray: Option<XRay>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct XRay {
     pub sdk_version: Option<String>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Ecs {
-    /// The container ID of the container running your application.
+    /// The hostname of the container running your application.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub container: Option<String>,
+    /// The full container ID of the container running your application.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_id: Option<String>,
+    /// The ARN of the container running your application.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_arn: Option<String>,
+    /// The ARN of the task the container belongs to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task_arn: Option<String>,
+    /// The family of the task definition the task was started from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task_family: Option<String>,
+    /// The ARN, or name, of the cluster the task runs in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+    /// How the task is run, such as EC2 or FARGATE.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub launch_type: Option<String>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// A CloudWatch Logs log group your application writes to
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CloudwatchLogs {
+    /// The name of the log group.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_group: Option<String>,
+    /// The ARN of the log group.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arn: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Ec2 {
     /// The instance ID of the EC2 instance.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -239,7 +820,7 @@ pub struct Ec2 {
 }
 
 /// Information about an Elastic Beanstalk environment. You can find this information in a file named /var/elasticbeanstalk/xray/environment.conf on the latest Elastic Beanstalk platforms.
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ElasticBeanstalk {
     /// The name of the environment.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -252,21 +833,24 @@ pub struct ElasticBeanstalk {
     pub deployment_id: Option<usize>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Tracing {
     /// version of sdk
     pub sdk: String,
 }
 
 /// Detailed representation of an exception
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Exception {
     /// A 64-bit identifier for the exception, unique among segments in the same trace, in 16 hexadecimal digits.
     pub id: String,
     /// The exception message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub messages: Option<String>,
+    pub message: Option<String>,
     /// The exception type.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// boolean indicating that the exception was caused by an error returned by a downstream service.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub remote: Option<bool>,
     /// integer indicating the number of stack frames that are omitted from the stack.
@@ -279,18 +863,19 @@ pub struct Exception {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cause: Option<String>,
     /// array of stackFrame objects.
+    #[serde(default)]
     pub stack: Vec<StackFrame>,
 }
 
 /// A summary of a single operation within a stack trace
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct StackFrame {
     /// The relative path to the file.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
     /// The line in the file.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub line: Option<String>,
+    pub line: Option<u32>,
     /// The function or method name.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
@@ -327,9 +912,78 @@ impl fmt::LowerHex for Bytes<'_> {
 
 #[cfg(test)]
 mod tests {
-    use super::Bytes;
+    use super::*;
     #[test]
     fn formats_lowerhex() {
         assert_eq!(format!("{:x}", Bytes(b"test")), "74657374")
     }
+
+    #[test]
+    fn independent_subsegment_representation() {
+        let segment = Segment::begin("service");
+        let mut subsegment = segment.subsegment("query");
+        subsegment.namespace = Some(Namespace::Remote);
+        subsegment.end();
+        let value = serde_json::to_value(&subsegment).expect("failed to serialize");
+        assert_eq!(value["type"], "subsegment");
+        assert_eq!(value["namespace"], "remote");
+        assert_eq!(value["trace_id"], segment.trace_id.to_string());
+        assert_eq!(value["parent_id"], segment.id.to_string());
+        assert!(subsegment.validate().is_ok());
+
+        subsegment.embedded();
+        let value = serde_json::to_value(&subsegment).expect("failed to serialize");
+        assert!(value.get("type").is_none());
+        assert!(value.get("trace_id").is_none());
+        assert!(value.get("parent_id").is_none());
+    }
+
+    #[test]
+    fn subsegment_deserialize() {
+        let subsegment = serde_json::from_str::<Subsegment>(
+            r#"{
+                "id": "53995c3f42cd8ad8",
+                "name": "www2.example.com",
+                "start_time": 1478293361.271,
+                "end_time": 1478293361.449,
+                "type": "subsegment",
+                "trace_id": "1-581cf771-a006649127e371903a2de979",
+                "parent_id": "defdfd9912dc5a56",
+                "namespace": "remote",
+                "precursor_ids": ["f64f2c9ee3e5a1c2"]
+            }"#,
+        )
+        .expect("failed to deserialize");
+        assert!(subsegment.is_independent());
+        assert_eq!(subsegment.namespace, Some(Namespace::Remote));
+        assert_eq!(subsegment.precursor_ids.len(), 1);
+        assert!(subsegment.validate().is_ok());
+    }
+
+    #[test]
+    fn subsegment_validation() {
+        let mut subsegment = Subsegment::begin("query");
+        assert!(
+            subsegment.validate().is_err(),
+            "neither ended nor in progress"
+        );
+        subsegment.in_progress = true;
+        assert!(subsegment.validate().is_ok());
+        subsegment.end();
+
+        subsegment
+            .subsegments
+            .push(Segment::begin("root").subsegment("nested"));
+        assert!(
+            subsegment.validate().is_err(),
+            "embedded independent subsegment"
+        );
+        subsegment.subsegments[0].embedded().end();
+        assert!(subsegment.validate().is_ok());
+
+        assert!(Subsegment::begin("").validate().is_err());
+        let mut invalid = Subsegment::begin("select * from users");
+        invalid.end();
+        assert!(invalid.validate().is_err());
+    }
 }
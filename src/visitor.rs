@@ -0,0 +1,356 @@
+//! Visitors which read recorded span fields
+
+use crate::{
+    sampling::SamplingRequest,
+    types::{
+        header::{Header, ParseMode},
+        ids::SegmentId,
+        time::Seconds,
+        types::{Annotation, Document, Exception, Namespace, Request, Response, StackFrame},
+    },
+};
+use serde_json::{json, Map, Number, Value};
+use std::{convert::TryFrom, error::Error, fmt};
+use tracing::{
+    field::{Field, Visit},
+    Metadata,
+};
+
+/// Reads the fields of a root span which determine the trace it belongs to
+///
+/// An incoming trace header is read from the [`Header::NAME`] field in
+/// [`ParseMode::Lenient`] mode. Headers without a valid `Root` are ignored, so
+/// that a malformed header starts a new trace rather than losing the request.
+/// The `http.method`, `http.url` and `http.host` fields describe the request
+/// for sampling.
+#[derive(Default)]
+pub(crate) struct RootVisitor {
+    pub(crate) header: Option<Header>,
+    http_method: Option<String>,
+    http_url: Option<String>,
+    http_host: Option<String>,
+}
+
+impl RootVisitor {
+    /// Describes the request for a sampler
+    pub(crate) fn sampling_request<'a>(&'a self, service_name: &'a str) -> SamplingRequest<'a> {
+        SamplingRequest {
+            service_name,
+            host: self.http_host.as_deref(),
+            http_method: self.http_method.as_deref(),
+            url_path: self.http_url.as_deref().map(url_path),
+        }
+    }
+}
+
+/// The path of a URL, which may be absolute or relative
+fn url_path(url: &str) -> &str {
+    let path = match url.find("://") {
+        Some(scheme) => {
+            let authority = &url[scheme + 3..];
+            authority.find('/').map_or("/", |start| &authority[start..])
+        }
+        None => url,
+    };
+    path.split(['?', '#']).next().unwrap_or(path)
+}
+
+impl Visit for RootVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            Header::NAME => self.header = Header::parse(value, ParseMode::Lenient).ok(),
+            "http.method" => self.http_method = Some(value.into()),
+            "http.url" => self.http_url = Some(value.into()),
+            "http.host" => self.http_host = Some(value.into()),
+            _ => (),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            Header::NAME | "http.method" | "http.url" | "http.host" => {
+                self.record_str(field, &format!("{:?}", value))
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Records span fields as annotations and metadata on a document
+///
+/// Annotation keys may only contain alphanumeric characters and underscores,
+/// so any other character in a field name is replaced with an underscore.
+///
+/// Fields describing an HTTP request and its response fill in the document's
+/// `http` object instead:
+///
+/// - `http.method`, `http.url`, `http.client_ip` and `http.user_agent`
+/// - `http.x_forwarded_for` and `http.traced`, booleans
+/// - `http.status_code`, which also marks the document as throttled, errored
+///   or faulted
+/// - `http.content_length`, the length of the response body
+///
+/// The `xray.name` field replaces the name of the document, so that it need
+/// not be the name of the span, and `xray.namespace` marks a subsegment as
+/// recording a call to an `aws` or `remote` service.
+pub(crate) struct FieldVisitor<'a> {
+    pub(crate) document: &'a mut Document,
+    pub(crate) annotation_prefix: Option<&'a str>,
+}
+
+impl FieldVisitor<'_> {
+    /// Namespace span fields are recorded under in segment metadata
+    const METADATA_NAMESPACE: &'static str = "default";
+
+    fn record(&mut self, field: &Field, annotation: Annotation, metadata: Value) {
+        let name = field.name();
+        if name == Header::NAME || self.record_reserved(name, &metadata) {
+            return;
+        }
+        match self
+            .annotation_prefix
+            .map(|prefix| name.strip_prefix(prefix))
+        {
+            None => self.annotate(name, annotation),
+            Some(Some(key)) => self.annotate(key, annotation),
+            Some(None) => {
+                self.document
+                    .add_metadata(Self::METADATA_NAMESPACE, name, metadata);
+            }
+        }
+    }
+
+    /// Records a field describing the document itself or an HTTP request or
+    /// response, returning whether it was one
+    fn record_reserved(&mut self, name: &str, value: &Value) -> bool {
+        let string = || value.as_str().map(String::from);
+        let number = || {
+            value
+                .as_u64()
+                .or_else(|| value.as_str().and_then(|value| value.parse().ok()))
+        };
+        match name {
+            "http.method" => self.request().method = string(),
+            "http.url" => self.request().url = string(),
+            "http.client_ip" => self.request().client_ip = string(),
+            "http.user_agent" => self.request().user_agent = string(),
+            "http.x_forwarded_for" => self.request().x_forwarded_for = value.as_bool(),
+            "http.traced" => self.request().traced = value.as_bool(),
+            // only describes the request for sampling, see `RootVisitor`
+            "http.host" => (),
+            "http.status_code" => {
+                if let Some(status) = number().and_then(|status| u16::try_from(status).ok()) {
+                    self.document.record_status(status);
+                }
+            }
+            "http.content_length" => {
+                self.document
+                    .http_mut()
+                    .response
+                    .get_or_insert_with(Response::default)
+                    .content_length = number();
+            }
+            "xray.name" => {
+                if let Some(name) = value.as_str() {
+                    self.document.rename(name);
+                }
+            }
+            "xray.namespace" => {
+                if let Document::Subsegment(subsegment) = self.document {
+                    subsegment.namespace = match value.as_str() {
+                        Some("aws") => Some(Namespace::Aws),
+                        Some("remote") => Some(Namespace::Remote),
+                        _ => None,
+                    };
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn request(&mut self) -> &mut Request {
+        self.document
+            .http_mut()
+            .request
+            .get_or_insert_with(Request::default)
+    }
+
+    fn annotate(&mut self, key: &str, annotation: Annotation) {
+        let key = key
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        self.document.annotate(key, annotation);
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, Annotation::Bool(value), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, Annotation::Number(value.into()), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, Annotation::Number(value.into()), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        match Number::from_f64(value) {
+            Some(number) => self.record(field, Annotation::Number(number), value.into()),
+            None => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, Annotation::String(value.into()), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = Value::String(format!("{:?}", value));
+        if field.name() != Header::NAME && !self.record_reserved(field.name(), &value) {
+            self.document
+                .add_metadata(Self::METADATA_NAMESPACE, field.name(), value);
+        }
+    }
+}
+
+/// Collects the message, fields and errors recorded by an event
+#[derive(Default)]
+pub(crate) struct EventVisitor {
+    message: Option<String>,
+    fields: Map<String, Value>,
+    /// The name of each error field, along with the messages of the error and
+    /// its sources
+    errors: Vec<(&'static str, Vec<String>)>,
+}
+
+impl EventVisitor {
+    /// A timestamped log entry describing the event
+    pub(crate) fn log_entry(&self, metadata: &Metadata) -> Value {
+        json!({
+            "timestamp": Seconds::now(),
+            "level": metadata.level().to_string(),
+            "target": metadata.target(),
+            "message": self.message,
+            "fields": self.fields,
+        })
+    }
+
+    /// Exceptions describing the event, along with the chain of sources of any
+    /// errors it recorded
+    ///
+    /// An event with both a message and errors is recorded as an exception
+    /// with its message, caused by the first error. Errors are of the kind
+    /// named by their field.
+    pub(crate) fn exceptions(&self, metadata: &Metadata) -> Vec<Exception> {
+        let stack = vec![StackFrame {
+            path: metadata.file().map(String::from),
+            line: metadata.line(),
+            label: metadata.module_path().map(String::from),
+        }];
+        if self.errors.is_empty() {
+            return vec![Exception {
+                id: SegmentId::new().to_string(),
+                message: self.message.clone(),
+                stack,
+                ..Exception::default()
+            }];
+        }
+        let mut exceptions = Vec::new();
+        if let Some(message) = &self.message {
+            exceptions.push(Exception {
+                id: SegmentId::new().to_string(),
+                message: Some(message.clone()),
+                stack: stack.clone(),
+                ..Exception::default()
+            });
+        }
+        for (kind, chain) in &self.errors {
+            let ids = chain
+                .iter()
+                .map(|_| SegmentId::new().to_string())
+                .collect::<Vec<_>>();
+            if let [event] = exceptions.as_mut_slice() {
+                event.cause = Some(ids[0].clone());
+            }
+            for (position, message) in chain.iter().enumerate() {
+                let head = position == 0;
+                exceptions.push(Exception {
+                    id: ids[position].clone(),
+                    message: Some(message.clone()),
+                    kind: Some(kind.to_string()).filter(|_| head),
+                    cause: ids.get(position + 1).cloned(),
+                    stack: if head { stack.clone() } else { Vec::new() },
+                    ..Exception::default()
+                });
+            }
+        }
+        exceptions
+    }
+}
+
+impl Visit for EventVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = Some(value.into());
+        } else {
+            self.fields.insert(field.name().into(), value.into());
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+        let mut chain = vec![value.to_string()];
+        let mut source = value.source();
+        while let Some(error) = source {
+            chain.push(error.to_string());
+            source = error.source();
+        }
+        self.fields
+            .insert(field.name().into(), chain[0].clone().into());
+        self.errors.push((field.name(), chain));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{:?}", value));
+        } else {
+            self.fields
+                .insert(field.name().into(), format!("{:?}", value).into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::url_path;
+
+    #[test]
+    fn extracts_url_path() {
+        assert_eq!(
+            url_path("https://example.com/api/orders?id=1"),
+            "/api/orders"
+        );
+        assert_eq!(url_path("https://example.com"), "/");
+        assert_eq!(url_path("/api/orders#top"), "/api/orders");
+    }
+}